@@ -0,0 +1,146 @@
+//! Automatic frequency correction.
+//!
+//! A tuning error shifts the received signal away from the center of the channel, which
+//! shows up as a DC offset in the FM demodulator output. Since C4FM symbols are balanced
+//! around zero, the mean output over a couple seconds of control channel measures the
+//! error directly.
+
+use consts::BASEBAND_SAMPLE_RATE;
+
+/// Number of baseband samples averaged into each estimate.
+const WINDOW: usize = BASEBAND_SAMPLE_RATE as usize * 2;
+
+/// Fraction of each estimated error that's corrected, to smooth out noisy estimates.
+const LOOP_GAIN: f32 = 0.5;
+
+/// Corrections smaller than this (ppm) are skipped to avoid needless retunes.
+const DEADBAND: f32 = 0.05;
+
+/// Result of one estimation window.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AfcEstimate {
+    /// Measured tuning error (Hz), positive if the signal is above the tuned frequency.
+    pub error: f32,
+    /// Total correction (ppm) to apply to tuned frequencies.
+    pub correction: f32,
+    /// Whether the correction changed enough to be applied.
+    pub changed: bool,
+}
+
+/// Estimates the tuning error from demodulator DC offset and tracks the correction.
+pub struct Afc {
+    /// Frequency deviation (Hz) represented by a demodulated value of 1.0.
+    deviation: f32,
+    /// Sum of demodulated values in the current window.
+    sum: f32,
+    /// Number of samples in the current window.
+    count: usize,
+    /// Whether a signal was decoded during the current window.
+    signal: bool,
+    /// Current correction (ppm).
+    correction: f32,
+}
+
+impl Afc {
+    /// Create a new `Afc` for a demodulator with the given frequency deviation (Hz).
+    pub fn new(deviation: u32) -> Self {
+        Afc {
+            deviation: deviation as f32,
+            sum: 0.0,
+            count: 0,
+            signal: false,
+            correction: 0.0,
+        }
+    }
+
+    /// Get the current correction (ppm).
+    pub fn correction(&self) -> f32 { self.correction }
+
+    /// Note that the channel was successfully decoded in the current window.
+    ///
+    /// Windows without a decoded signal are assumed to be noise and discarded.
+    pub fn mark_signal(&mut self) {
+        self.signal = true;
+    }
+
+    /// Discard the current window, such as after retuning.
+    pub fn reset(&mut self) {
+        self.sum = 0.0;
+        self.count = 0;
+        self.signal = false;
+    }
+
+    /// Add a chunk with the given mean demodulated value and number of samples, received
+    /// on the given frequency (Hz).
+    ///
+    /// Return an estimate when the chunk completes a window.
+    pub fn feed(&mut self, dc: f32, samples: usize, freq: u32) -> Option<AfcEstimate> {
+        self.sum += dc * samples as f32;
+        self.count += samples;
+
+        if self.count < WINDOW {
+            return None;
+        }
+
+        let signal = self.signal;
+        let error = self.sum / self.count as f32 * self.deviation;
+
+        self.reset();
+
+        if !signal {
+            return None;
+        }
+
+        let step = LOOP_GAIN * error / freq as f32 * 1.0e6;
+        let changed = step.abs() >= DEADBAND;
+
+        if changed {
+            self.correction += step;
+        }
+
+        Some(AfcEstimate {
+            error: error,
+            correction: self.correction,
+            changed: changed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_afc() {
+        let mut afc = Afc::new(5000);
+
+        // Not enough samples.
+        afc.mark_signal();
+        assert_eq!(afc.feed(0.2, 1000, 850_000_000), None);
+
+        // No signal seen.
+        afc.reset();
+        assert_eq!(afc.feed(0.2, WINDOW, 850_000_000), None);
+        assert_eq!(afc.correction(), 0.0);
+
+        // 1kHz error.
+        afc.mark_signal();
+        let e = afc.feed(0.2, WINDOW, 1_000_000_000).unwrap();
+        assert!((e.error - 1000.0).abs() < 1e-3);
+        assert!((e.correction - 0.5).abs() < 1e-5);
+        assert!(e.changed);
+
+        // Negative error partially undoes the correction.
+        afc.mark_signal();
+        afc.feed(-0.04, WINDOW / 2, 1_000_000_000);
+        let e = afc.feed(-0.04, WINDOW / 2, 1_000_000_000).unwrap();
+        assert!((e.error + 200.0).abs() < 1e-3);
+        assert!((e.correction - 0.4).abs() < 1e-5);
+
+        // Tiny errors are ignored.
+        afc.mark_signal();
+        let e = afc.feed(0.001, WINDOW, 1_000_000_000).unwrap();
+        assert!(!e.changed);
+        assert!((e.correction - 0.4).abs() < 1e-5);
+    }
+}
@@ -10,12 +10,12 @@ use imbe::frame::ReceivedFrame;
 use map_in_place::MapInPlace;
 use p25::voice::frame::VoiceFrame;
 
-/// Messages for `AudioTask`.
+/// Messages for `AudioTask`, tagged with the tuner they came from.
 pub enum AudioEvent {
     /// A voice frame was received.
-    VoiceFrame(VoiceFrame),
+    VoiceFrame(usize, VoiceFrame),
     /// The current voice transmission has been terminated.
-    EndTransmission,
+    EndTransmission(usize),
 }
 
 /// Decodes voice frames and outputs them to a stream.
@@ -24,6 +24,10 @@ pub struct AudioTask<W: Write> {
     audio: AudioOutput<W>,
     /// Channel for messages.
     events: Receiver<AudioEvent>,
+    /// Tuner whose transmission is currently being played.
+    ///
+    /// Frames from other tuners are dropped so simultaneous calls aren't mixed together.
+    owner: Option<usize>,
 }
 
 impl<W: Write> AudioTask<W> {
@@ -32,20 +36,33 @@ impl<W: Write> AudioTask<W> {
         AudioTask {
             audio: audio,
             events: events,
+            owner: None,
         }
     }
 
-    /// Begin handling events, blocking the current thread.
+    /// Begin handling events, blocking the current thread until all senders have
+    /// disconnected.
     pub fn run(&mut self) {
-        loop {
-            match self.events.recv().expect("unable to receive audio event") {
-                AudioEvent::VoiceFrame(vf) => self.audio.play(&vf),
-                AudioEvent::EndTransmission => {
-                   self.audio.flush();
-                   self.audio.reset();
+        while let Ok(event) = self.events.recv() {
+            match event {
+                AudioEvent::VoiceFrame(src, vf) => {
+                    if *self.owner.get_or_insert(src) == src {
+                        self.audio.play(&vf);
+                    }
+                },
+                AudioEvent::EndTransmission(src) => {
+                    if self.owner.unwrap_or(src) != src {
+                        continue;
+                    }
+
+                    self.owner = None;
+                    self.audio.flush();
+                    self.audio.reset();
                 },
             }
         }
+
+        self.audio.flush();
     }
 }
 
@@ -0,0 +1,378 @@
+//! Recording format for demodulated baseband samples.
+//!
+//! A recording starts with a fixed header:
+//!
+//! | Bytes | Field                                            |
+//! |-------|--------------------------------------------------|
+//! | 4     | magic `P25B`                                     |
+//! | 1     | format version                                   |
+//! | 1     | sample endianness (0 for little, 1 for big)      |
+//! | 2     | reserved                                         |
+//! | 4     | sample rate (Hz)                                 |
+//! | 8     | start time (seconds since Unix epoch)            |
+//! | 4     | start time (nanoseconds past the second)         |
+//!
+//! It's followed by a sequence of records, each starting with a tag byte:
+//!
+//! - `0x01`: a 4-byte sample count followed by that many 32-bit float samples
+//! - `0x02`: a retune, with a 4-byte frequency (Hz) and a 1-byte channel kind (0 for
+//!   control, 1 for traffic)
+//!
+//! Header and record fields are little-endian, and samples are in the endianness given
+//! in the header.
+//!
+//! Recordings without the magic are read as the original format: raw native-endian f32
+//! samples with no metadata.
+
+use std::io::{Read, Write, Cursor, Chain, ErrorKind};
+use std::time::{SystemTime, Duration, UNIX_EPOCH};
+use std;
+
+/// Magic bytes at the start of a recording.
+pub const MAGIC: &'static [u8; 4] = b"P25B";
+
+/// Current format version.
+pub const VERSION: u8 = 1;
+
+/// Size of the header (bytes).
+const HEADER_BYTES: usize = 24;
+
+/// Maximum number of samples in a single record.
+const MAX_RECORD_SAMPLES: usize = 1 << 20;
+
+/// Tag for a samples record.
+const TAG_SAMPLES: u8 = 0x01;
+/// Tag for a retune record.
+const TAG_RETUNE: u8 = 0x02;
+
+/// Recording metadata.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Sample rate (Hz).
+    pub rate: u32,
+    /// Whether samples are big-endian.
+    pub big_endian: bool,
+    /// Time the recording started, relative to the Unix epoch.
+    pub start: Duration,
+}
+
+impl Header {
+    /// Create a header for native-endian samples at the given rate, starting now.
+    pub fn new(rate: u32) -> Self {
+        Header {
+            rate: rate,
+            big_endian: cfg!(target_endian = "big"),
+            start: SystemTime::now().duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::new(0, 0)),
+        }
+    }
+
+    fn write<W: Write>(&self, mut stream: W) -> std::io::Result<()> {
+        let mut buf = [0; HEADER_BYTES];
+
+        (&mut buf[..4]).copy_from_slice(&MAGIC[..]);
+        buf[4] = VERSION;
+        buf[5] = self.big_endian as u8;
+        write_le(&mut buf[8..12], self.rate as u64);
+        write_le(&mut buf[12..20], self.start.as_secs());
+        write_le(&mut buf[20..24], self.start.subsec_nanos() as u64);
+
+        stream.write_all(&buf[..])
+    }
+
+    /// Parse the header following the magic.
+    fn read<R: Read>(mut stream: R) -> std::io::Result<Self> {
+        let mut buf = [0; HEADER_BYTES - 4];
+        try!(stream.read_exact(&mut buf[..]));
+
+        if buf[0] != VERSION {
+            return Err(invalid("unsupported baseband format version"));
+        }
+
+        Ok(Header {
+            rate: read_le(&buf[4..8]) as u32,
+            big_endian: buf[1] != 0,
+            start: Duration::new(read_le(&buf[8..16]), read_le(&buf[16..20]) as u32),
+        })
+    }
+}
+
+/// Kinds of records in a recording.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    /// Samples were read into the caller's buffer.
+    Samples,
+    /// The receiver was retuned to the given frequency (Hz), either to a control channel
+    /// or to a traffic channel.
+    Retune {
+        freq: u32,
+        traffic: bool,
+    },
+}
+
+/// Writes baseband samples and retunes into a recording.
+pub struct BasebandWriter<W: Write> {
+    /// Stream to write to.
+    stream: W,
+}
+
+impl<W: Write> BasebandWriter<W> {
+    /// Create a new `BasebandWriter` over the given stream, writing a header for
+    /// recording at the given sample rate.
+    pub fn new(mut stream: W, rate: u32) -> std::io::Result<Self> {
+        try!(Header::new(rate).write(&mut stream));
+
+        Ok(BasebandWriter {
+            stream: stream,
+        })
+    }
+
+    /// Write a record of the given samples.
+    pub fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let mut buf = [TAG_SAMPLES, 0, 0, 0, 0];
+        write_le(&mut buf[1..], samples.len() as u64);
+
+        try!(self.stream.write_all(&buf[..]));
+
+        self.stream.write_all(unsafe {
+            std::slice::from_raw_parts(
+                samples.as_ptr() as *const u8,
+                samples.len() * std::mem::size_of::<f32>()
+            )
+        })
+    }
+
+    /// Write a record of a retune to the given frequency (Hz).
+    pub fn write_retune(&mut self, freq: u32, traffic: bool) -> std::io::Result<()> {
+        let mut buf = [TAG_RETUNE, 0, 0, 0, 0, traffic as u8];
+        write_le(&mut buf[1..5], freq as u64);
+
+        self.stream.write_all(&buf[..])
+    }
+
+    /// Flush the wrapped stream.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Reads baseband samples and retunes from a recording in either format.
+pub struct BasebandReader<R: Read> {
+    /// Stream to read from, including any bytes consumed while detecting the format.
+    stream: Chain<Cursor<Vec<u8>>, R>,
+    /// Header of the recording, or `None` for the original format.
+    header: Option<Header>,
+    /// Scratch buffer for raw sample bytes.
+    bytes: Vec<u8>,
+}
+
+impl<R: Read> BasebandReader<R> {
+    /// Create a new `BasebandReader` over the given stream, detecting the format.
+    pub fn new(mut stream: R) -> std::io::Result<Self> {
+        let mut magic = [0; 4];
+        let len = try!(read_full(&mut stream, &mut magic[..]));
+
+        if len == magic.len() && &magic == MAGIC {
+            let header = try!(Header::read(&mut stream));
+
+            return Ok(BasebandReader {
+                stream: Cursor::new(vec![]).chain(stream),
+                header: Some(header),
+                bytes: vec![],
+            });
+        }
+
+        Ok(BasebandReader {
+            stream: Cursor::new(magic[..len].to_vec()).chain(stream),
+            header: None,
+            bytes: vec![],
+        })
+    }
+
+    /// Get the recording header, or `None` if the recording is in the original format.
+    pub fn header(&self) -> Option<Header> { self.header }
+
+    /// Read the next record, replacing the contents of the given buffer if the record
+    /// holds samples.
+    ///
+    /// Return `None` at the end of the recording.
+    pub fn read_record(&mut self, samples: &mut Vec<f32>)
+        -> std::io::Result<Option<Record>>
+    {
+        let header = match self.header {
+            Some(h) => h,
+            None => return self.read_raw(samples),
+        };
+
+        let mut tag = [0; 1];
+
+        if try!(read_full(&mut self.stream, &mut tag[..])) == 0 {
+            return Ok(None);
+        }
+
+        match tag[0] {
+            TAG_SAMPLES => {
+                let mut buf = [0; 4];
+                try!(self.stream.read_exact(&mut buf[..]));
+
+                let count = read_le(&buf[..]) as usize;
+
+                if count > MAX_RECORD_SAMPLES {
+                    return Err(invalid("baseband record too large"));
+                }
+
+                self.bytes.resize(count * 4, 0);
+                try!(self.stream.read_exact(&mut self.bytes[..]));
+
+                decode_samples(&self.bytes[..], header.big_endian, samples);
+
+                Ok(Some(Record::Samples))
+            },
+            TAG_RETUNE => {
+                let mut buf = [0; 5];
+                try!(self.stream.read_exact(&mut buf[..]));
+
+                Ok(Some(Record::Retune {
+                    freq: read_le(&buf[..4]) as u32,
+                    traffic: buf[4] != 0,
+                }))
+            },
+            _ => Err(invalid("unknown baseband record")),
+        }
+    }
+
+    /// Read the next chunk of samples in the original format.
+    fn read_raw(&mut self, samples: &mut Vec<f32>) -> std::io::Result<Option<Record>> {
+        self.bytes.resize(32768, 0);
+
+        // Any trailing partial sample is dropped.
+        let len = try!(read_full(&mut self.stream, &mut self.bytes[..])) / 4 * 4;
+
+        if len == 0 {
+            return Ok(None);
+        }
+
+        decode_samples(&self.bytes[..len], cfg!(target_endian = "big"), samples);
+
+        Ok(Some(Record::Samples))
+    }
+}
+
+/// Decode the given bytes to samples with the given endianness.
+fn decode_samples(bytes: &[u8], big_endian: bool, samples: &mut Vec<f32>) {
+    samples.clear();
+
+    samples.extend(bytes.chunks(4).map(|b| {
+        let bits = if big_endian {
+            (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+        } else {
+            (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+        };
+
+        unsafe { std::mem::transmute::<u32, f32>(bits) }
+    }));
+}
+
+/// Read into the given buffer until it's full or the stream ends, returning the number of
+/// bytes read.
+fn read_full<R: Read>(mut stream: R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match stream.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+/// Read a little-endian word from the given bytes.
+fn read_le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |w, &b| w << 8 | b as u64)
+}
+
+/// Write the given word in little-endian order, truncated to the length of the given
+/// buffer.
+fn write_le(buf: &mut [u8], w: u64) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (w >> (i * 8)) as u8;
+    }
+}
+
+fn invalid(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buf = vec![];
+
+        {
+            let mut w = BasebandWriter::new(&mut buf, 48000).unwrap();
+            w.write_retune(851_012_500, false).unwrap();
+            w.write_samples(&[0.5, -1.25, 3.0]).unwrap();
+            w.write_retune(852_500_000, true).unwrap();
+            w.write_samples(&[]).unwrap();
+        }
+
+        let mut r = BasebandReader::new(Cursor::new(buf)).unwrap();
+        let mut samples = vec![];
+
+        let h = r.header().unwrap();
+        assert_eq!(h.rate, 48000);
+        assert_eq!(h.big_endian, cfg!(target_endian = "big"));
+
+        assert_eq!(r.read_record(&mut samples).unwrap(), Some(Record::Retune {
+            freq: 851_012_500,
+            traffic: false,
+        }));
+        assert_eq!(r.read_record(&mut samples).unwrap(), Some(Record::Samples));
+        assert_eq!(samples, vec![0.5, -1.25, 3.0]);
+        assert_eq!(r.read_record(&mut samples).unwrap(), Some(Record::Retune {
+            freq: 852_500_000,
+            traffic: true,
+        }));
+        assert_eq!(r.read_record(&mut samples).unwrap(), Some(Record::Samples));
+        assert_eq!(samples, vec![]);
+        assert_eq!(r.read_record(&mut samples).unwrap(), None);
+    }
+
+    #[test]
+    fn test_raw() {
+        let orig = [0.5f32, -1.25, 3.0];
+        let mut bytes = unsafe {
+            std::slice::from_raw_parts(orig.as_ptr() as *const u8, 12)
+        }.to_vec();
+
+        // Partial trailing sample.
+        bytes.push(0);
+
+        let mut r = BasebandReader::new(Cursor::new(bytes)).unwrap();
+        let mut samples = vec![];
+
+        assert_eq!(r.header(), None);
+        assert_eq!(r.read_record(&mut samples).unwrap(), Some(Record::Samples));
+        assert_eq!(&samples[..], &orig[..]);
+        assert_eq!(r.read_record(&mut samples).unwrap(), None);
+    }
+
+    #[test]
+    fn test_endian() {
+        let mut samples = vec![];
+
+        decode_samples(&[0x3f, 0x80, 0, 0], true, &mut samples);
+        assert_eq!(samples, vec![1.0]);
+
+        decode_samples(&[0, 0, 0x80, 0x3f], false, &mut samples);
+        assert_eq!(samples, vec![1.0]);
+    }
+}
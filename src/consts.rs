@@ -9,6 +9,10 @@ pub const BUF_SAMPLES: usize = BUF_BYTES / 2;
 pub const SDR_SAMPLE_RATE: u32 = 240_000;
 /// Downconverted baseband sample rate.
 pub const BASEBAND_SAMPLE_RATE: u32 = 48000;
+/// P25 symbol rate (symbols per second).
+pub const SYMBOL_RATE: u32 = 4800;
+/// Assumed FM frequency deviation (Hz).
+pub const FM_DEVIATION: u32 = 5000;
 
 #[cfg(test)]
 mod test {
@@ -0,0 +1,274 @@
+//! CQPSK demodulation for simulcast (LSM) systems.
+//!
+//! Simulcast sites transmit the same symbols from several towers, and the delayed copies
+//! smear the instantaneous frequency that an FM discriminator relies on. CQPSK instead
+//! recovers symbol timing, equalizes out the multipath, and measures the phase change
+//! from one symbol to the next, which is the same quantity C4FM carries as frequency.
+//! The phase changes are then written out as C4FM-like baseband so the rest of the
+//! receiver can't tell the difference.
+
+use std::f32::consts::PI;
+
+use num::complex::Complex32;
+use num::traits::Zero;
+
+use consts::{BASEBAND_SAMPLE_RATE, SYMBOL_RATE, FM_DEVIATION};
+
+/// Number of baseband samples per symbol.
+const SAMPLES_PER_SYMBOL: f32 = BASEBAND_SAMPLE_RATE as f32 / SYMBOL_RATE as f32;
+
+/// Smoothing factor for the signal level estimate.
+const AGC_ALPHA: f32 = 1.0e-3;
+
+/// Loop gain of the symbol timing recovery.
+const TIMING_GAIN: f32 = 0.025;
+
+/// Largest timing adjustment (samples) made at one symbol.
+const TIMING_MAX_STEP: f32 = 0.5;
+
+/// Number of equalizer taps, spanning a few symbols of simulcast delay spread.
+const EQUALIZER_TAPS: usize = 7;
+
+/// Adaptation rate of the equalizer.
+const EQUALIZER_RATE: f32 = 2.0e-3;
+
+/// Normalizes the signal to unit amplitude.
+struct Agc {
+    /// Running estimate of the signal amplitude.
+    level: f32,
+}
+
+impl Agc {
+    /// Create a new `Agc` with a unit level estimate.
+    pub fn new() -> Self {
+        Agc {
+            level: 1.0,
+        }
+    }
+
+    /// Scale the given sample by the current level estimate and update the estimate.
+    pub fn feed(&mut self, s: Complex32) -> Complex32 {
+        self.level += (s.norm() - self.level) * AGC_ALPHA;
+
+        if self.level > 0.0 {
+            s / self.level
+        } else {
+            s
+        }
+    }
+}
+
+/// Recovers symbol timing with a Gardner detector, which only needs two samples per
+/// symbol and doesn't depend on carrier phase.
+struct SymbolTiming {
+    /// Number of samples (possibly fractional) until the next strobe.
+    next: f32,
+    /// Whether the next strobe is halfway between symbols.
+    midpoint: bool,
+    /// Previous input sample.
+    prev: Complex32,
+    /// Sample halfway between the last symbol and the next.
+    mid: Complex32,
+    /// Last symbol sample.
+    last: Complex32,
+}
+
+impl SymbolTiming {
+    /// Create a new `SymbolTiming` with an arbitrary initial phase.
+    pub fn new() -> Self {
+        SymbolTiming {
+            next: SAMPLES_PER_SYMBOL / 2.0,
+            midpoint: false,
+            prev: Complex32::zero(),
+            mid: Complex32::zero(),
+            last: Complex32::zero(),
+        }
+    }
+
+    /// Feed in the given sample, returning a sample at the symbol center when one falls
+    /// between the previous sample and this one.
+    pub fn feed(&mut self, s: Complex32) -> Option<Complex32> {
+        let prev = self.prev;
+        self.prev = s;
+
+        self.next -= 1.0;
+
+        if self.next > 0.0 {
+            return None;
+        }
+
+        // Interpolate between the two samples around the strobe.
+        let y = prev + (s - prev) * (1.0 + self.next);
+
+        if self.midpoint {
+            self.midpoint = false;
+            self.mid = y;
+            self.next += SAMPLES_PER_SYMBOL / 2.0;
+
+            return None;
+        }
+
+        // The midpoint sample sits on a transition when timing is right, and it leans
+        // toward the symbol it's closer to otherwise.
+        let err = ((y - self.last) * self.mid.conj()).re;
+        let step = (err * TIMING_GAIN).max(-TIMING_MAX_STEP).min(TIMING_MAX_STEP);
+
+        self.midpoint = true;
+        self.last = y;
+        self.next += SAMPLES_PER_SYMBOL / 2.0 - step;
+
+        Some(y)
+    }
+}
+
+/// Adaptive equalizer using the constant modulus algorithm, which undoes multipath
+/// without needing to know the transmitted symbols.
+struct Equalizer {
+    /// Filter taps.
+    taps: [Complex32; EQUALIZER_TAPS],
+    /// Recent symbols, most recent first.
+    hist: [Complex32; EQUALIZER_TAPS],
+}
+
+impl Equalizer {
+    /// Create a new `Equalizer` that initially passes symbols through unchanged.
+    pub fn new() -> Self {
+        let mut taps = [Complex32::zero(); EQUALIZER_TAPS];
+        taps[EQUALIZER_TAPS / 2] = Complex32::new(1.0, 0.0);
+
+        Equalizer {
+            taps: taps,
+            hist: [Complex32::zero(); EQUALIZER_TAPS],
+        }
+    }
+
+    /// Equalize the given symbol and adapt the taps toward unit modulus.
+    pub fn feed(&mut self, s: Complex32) -> Complex32 {
+        for i in (1..EQUALIZER_TAPS).rev() {
+            self.hist[i] = self.hist[i - 1];
+        }
+
+        self.hist[0] = s;
+
+        let y = self.taps.iter().zip(self.hist.iter())
+            .fold(Complex32::zero(), |sum, (&w, &x)| sum + w * x);
+
+        let err = y * (y.norm_sqr() - 1.0);
+
+        for (w, &x) in self.taps.iter_mut().zip(self.hist.iter()) {
+            *w = *w - err * x.conj() * EQUALIZER_RATE;
+        }
+
+        y
+    }
+}
+
+/// Demodulates a 48kHz CQPSK channel to C4FM-equivalent baseband.
+pub struct CqpskDemod {
+    /// Normalizes the signal level.
+    agc: Agc,
+    /// Recovers symbol timing.
+    timing: SymbolTiming,
+    /// Removes multipath.
+    equalizer: Equalizer,
+    /// Previous equalized symbol.
+    prev: Complex32,
+    /// Baseband level of the current symbol.
+    level: f32,
+}
+
+impl CqpskDemod {
+    /// Create a new `CqpskDemod` with no symbol history.
+    pub fn new() -> Self {
+        CqpskDemod {
+            agc: Agc::new(),
+            timing: SymbolTiming::new(),
+            equalizer: Equalizer::new(),
+            prev: Complex32::zero(),
+            level: 0.0,
+        }
+    }
+
+    /// Demodulate the given sample, returning the baseband level of the most recent
+    /// symbol.
+    ///
+    /// The level is held across each symbol period, and a phase change produces the same
+    /// level an FM demodulator gives for the equivalent C4FM frequency.
+    pub fn feed(&mut self, s: Complex32) -> f32 {
+        let s = self.agc.feed(s);
+
+        if let Some(sym) = self.timing.feed(s) {
+            let sym = self.equalizer.feed(sym);
+            let phase = (sym * self.prev.conj()).arg();
+
+            self.prev = sym;
+            self.level = phase_level(phase);
+        }
+
+        self.level
+    }
+}
+
+/// Convert the given phase change (radians) over one symbol to the corresponding FM
+/// demodulator output, where full deviation gives a level of 1.
+fn phase_level(phase: f32) -> f32 {
+    phase * SYMBOL_RATE as f32 / (2.0 * PI * FM_DEVIATION as f32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f32::consts::PI;
+    use num::complex::Complex32;
+    use quality::test::noise;
+
+    #[test]
+    fn test_phase_level() {
+        // Outer C4FM symbols deviate 1800Hz.
+        assert!((phase_level(3.0 * PI / 4.0) - 0.36).abs() < 1e-6);
+        assert!((phase_level(-PI / 4.0) + 0.12).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_demod() {
+        let phases = [3.0 * PI / 4.0, PI / 4.0, -PI / 4.0, -3.0 * PI / 4.0];
+        let symbols = noise(2000).iter().map(|&x| phases[((x + 1.0) * 2.0) as usize])
+                                 .collect::<Vec<_>>();
+
+        let mut demod = CqpskDemod::new();
+        let mut phase = 0.0;
+        let mut levels = vec![];
+        let mut samples = vec![];
+
+        for &p in symbols.iter() {
+            let start = Complex32::from_polar(&0.3, &phase);
+            phase += p;
+            let end = Complex32::from_polar(&0.3, &phase);
+
+            // Move between constellation points with a raised-cosine transition.
+            for i in 0..10 {
+                let t = i as f32 / 10.0;
+                let w = (1.0 - (PI * t).cos()) / 2.0;
+                samples.push(start + (end - start) * w);
+            }
+        }
+
+        // Add a weaker copy from a farther simulcast site, a symbol behind.
+        for i in 0..samples.len() {
+            let echo = if i < 10 { Complex32::new(0.0, 0.0) } else { samples[i - 10] };
+            levels.push(demod.feed(samples[i] + echo * Complex32::new(0.0, 0.4)));
+        }
+
+        let expected = symbols.iter().map(|&p| phase_level(p)).collect::<Vec<_>>();
+
+        // Once locked, the symbol levels follow the transmitted phases after a fixed
+        // delay.
+        let found = (0..10).any(|delay| {
+            (1000..1900).all(|i| {
+                (levels[i * 10 + 5] - expected[i - delay]).abs() < 0.05
+            })
+        });
+
+        assert!(found);
+    }
+}
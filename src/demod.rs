@@ -1,5 +1,7 @@
 //! Demodulation and other signal processing.
 
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Sender, Receiver};
 use std;
 
@@ -10,56 +12,131 @@ use mio;
 use num::complex::Complex32;
 use num::traits::Zero;
 use p25_filts::{DecimFIR, BandpassFIR, DeemphFIR};
-use pool::{Pool, Checkout};
+use pool::Pool;
 use rtlsdr_iq::IQ;
 use static_decimate::{Decimator, DecimationFactor};
 use static_fir::FIRFilter;
 use throttle::Throttler;
 
-use hub::HubEvent;
+use cqpsk::CqpskDemod;
+use dsp::{DcBlocker, Nco, BasebandDecimator};
+use eye::EyeTap;
+use hub::{HubEvent, StateEvent};
+use quality;
 use recv::RecvEvent;
-use consts::{BUF_SAMPLES, BASEBAND_SAMPLE_RATE};
+use sdr::{SampleChunk, TapEvent};
+use spectrum::SpectrumAnalyzer;
+use squelch::Squelch;
+use consts::{BUF_SAMPLES, SDR_SAMPLE_RATE, BASEBAND_SAMPLE_RATE, FM_DEVIATION};
+
+/// Smoothing factor for DC removal, which at SDR sample rates notches out only a few Hz
+/// around DC.
+const DC_ALPHA: f32 = 1.0e-4;
+
+/// Modulation used by a P25 system.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Modulation {
+    /// Standard FM modulation with four frequency deviations.
+    C4fm,
+    /// Phase modulation used by simulcast (LSM) systems.
+    Cqpsk,
+}
 
 /// Demodulates raw I/Q signal to C4FM baseband.
 pub struct DemodTask {
-    /// Decimates I/Q signal.
+    /// SDR sample rate (Hz).
+    rate: u32,
+    /// Decimates I/Q signal at the normal SDR sample rate.
     decim: Decimator<Decimate5, DecimFIR>,
-    /// Channel-select lowpass filter.
-    bandpass: FIRFilter<BandpassFIR>,
-    /// Deemphasis lowpass filter.
-    deemph: FIRFilter<DeemphFIR>,
-    /// Demodulates FM signal.
-    demod: FmDemod,
+    /// Decimates I/Q signal at faster sample rates, if the SDR is running faster.
+    resample: Option<BasebandDecimator>,
+    /// Filters and demodulates the decimated signal.
+    demod: Demodulator,
     /// Channel for receiving I/Q sample chunks.
-    reader: Receiver<Checkout<Vec<u8>>>,
-    /// Channel for the hub.
-    hub: mio::channel::Sender<HubEvent>,
+    reader: Receiver<SampleChunk>,
+    /// Channel for the hub, if this demodulator reports signal measurements.
+    hub: Option<mio::channel::Sender<HubEvent>>,
     /// Channel for sending baseband sample chunks.
     chan: Sender<RecvEvent>,
+    /// DC removal and the oscillator that shifts the channel back to the center, if the
+    /// SDR is tuned off the channel.
+    shift: Option<(DcBlocker, Nco)>,
+    /// Spectrum of the raw samples, if it's reported to the hub, along with the channel
+    /// that notifies each retune.
+    spectrum: Option<(SpectrumAnalyzer, Receiver<TapEvent>)>,
+    /// Latest center frequency (Hz) the SDR was retuned to and the tuning generation it
+    /// started, if known.
+    tuned: Option<(u32, usize)>,
+    /// Tuning generation the spectrum is currently analyzing, if its center frequency
+    /// was known.
+    analyzed: Option<usize>,
+    /// Eye diagram tap on the baseband, if it can be turned on, and the flag that turns
+    /// it on.
+    eye: Option<(EyeTap, Arc<AtomicBool>)>,
 }
 
 impl DemodTask {
-    /// Create a new `DemodTask` that receives I/Q sample chunks from the first given
-    /// channel, sends events to the second given hub, and sends baseband sample chunks to
-    /// the third given channel.
-    pub fn new(reader: Receiver<Checkout<Vec<u8>>>,
-               hub: mio::channel::Sender<HubEvent>,
+    /// Create a new `DemodTask` for an SDR at the given sample rate (Hz), which must be a
+    /// multiple of the normal SDR sample rate, that receives I/Q sample chunks from the
+    /// first given channel, sends events to the second given hub (if any), and sends
+    /// baseband sample chunks to the third given channel.
+    pub fn new(rate: u32,
+               reader: Receiver<SampleChunk>,
+               hub: Option<mio::channel::Sender<HubEvent>>,
                chan: Sender<RecvEvent>)
         -> Self
     {
         DemodTask {
+            rate: rate,
             decim: Decimator::new(),
-            bandpass: FIRFilter::new(),
-            deemph: FIRFilter::new(),
-            // Assume a 5kHz frequency deviation.
-            demod: FmDemod::new(5000, BASEBAND_SAMPLE_RATE),
+            resample: if rate == SDR_SAMPLE_RATE {
+                None
+            } else {
+                Some(BasebandDecimator::new(rate))
+            },
+            demod: Demodulator::new(Modulation::C4fm),
             reader: reader,
             hub: hub,
             chan: chan,
+            shift: None,
+            spectrum: None,
+            tuned: None,
+            analyzed: None,
+            eye: None,
         }
     }
 
-    /// Begin demodulating, blocking the current thread.
+    /// Expect the SDR to be tuned the given offset (Hz) above each channel, removing the
+    /// DC spike and shifting the channel back to the center before decimation.
+    pub fn set_offset(&mut self, offset: i32) {
+        self.shift = if offset == 0 {
+            None
+        } else {
+            let freq = offset as f32 / self.rate as f32;
+            Some((DcBlocker::new(DC_ALPHA), Nco::new(freq)))
+        };
+    }
+
+    /// Report spectrum frames of the raw samples to the hub with the given number of
+    /// bins, which must be a supported transform size, at the given frames per second,
+    /// learning the SDR's center frequency from the given retune notifications.
+    pub fn enable_spectrum(&mut self, size: usize, fps: u32, tuned: Receiver<TapEvent>) {
+        self.spectrum = Some((SpectrumAnalyzer::new(self.rate, size, fps), tuned));
+    }
+
+    /// Report eye diagram captures of the baseband to the hub whenever the given flag is
+    /// set.
+    pub fn enable_eye(&mut self, flag: Arc<AtomicBool>) {
+        self.eye = Some((EyeTap::new(), flag));
+    }
+
+    /// Demodulate the channel with the given modulation.
+    pub fn set_modulation(&mut self, modulation: Modulation) {
+        self.demod = Demodulator::new(modulation);
+    }
+
+    /// Begin demodulating, blocking the current thread until the I/Q source is
+    /// exhausted.
     pub fn run(&mut self) {
         let mut pool = Pool::with_capacity(16, || vec![0.0; BUF_SAMPLES]);
         let mut samples = vec![Complex32::zero(); BUF_SAMPLES];
@@ -67,59 +144,216 @@ impl DemodTask {
         // Used to reduce the number of signal level messages sent.
         let mut notifier = Throttler::new(16);
 
-        loop {
-            let bytes = self.reader.recv().expect("unable to receive sdr samples");
-
-            // This is safe because it's transforming an array of N 8-bit words to an
-            // array of N/2 16-bit words.
-            let pairs = unsafe {
-                std::slice::from_raw_parts(bytes.as_ptr() as *const u16, BUF_SAMPLES)
-            };
-
+        while let Ok(chunk) = self.reader.recv() {
             // This is safe because it equals the original allocation length.
             unsafe { samples.set_len(BUF_SAMPLES); }
 
-            // Transform interleaved byte pairs to complex floating point samples.
-            pairs.iter()
-                 .map(|&s| unsafe { *IQ.get_unchecked(s as usize) })
-                 .collect_slice(&mut samples[..]);
+            iq_samples(&chunk.bytes[..], &mut samples[..]);
+
+            if let Some((ref mut sa, ref tuned)) = self.spectrum {
+                while let Ok(event) = tuned.try_recv() {
+                    if let TapEvent::Tuned(center, generation) = event {
+                        self.tuned = Some((center, generation));
+                    }
+                }
+
+                // The retune notification can arrive after the first chunks of its
+                // generation, so those are skipped until the frequency is known.
+                if self.analyzed != Some(chunk.generation) {
+                    match self.tuned {
+                        Some((center, generation)) if generation == chunk.generation => {
+                            sa.retune(center);
+                            self.analyzed = Some(generation);
+                        },
+                        _ => {},
+                    }
+                }
+
+                let frame = if self.analyzed == Some(chunk.generation) {
+                    sa.feed(&samples[..])
+                } else {
+                    None
+                };
+
+                match (frame, self.hub.as_ref()) {
+                    (Some(frame), Some(hub)) => {
+                        hub.send(HubEvent::State(StateEvent::UpdateSpectrum(frame)))
+                            .expect("unable to send spectrum");
+                    },
+                    _ => {},
+                }
+            }
+
+            // The channel sits below the center, and the DC spike is shifted up out of
+            // the channel along with it.
+            if let Some((ref mut dc, ref mut nco)) = self.shift {
+                samples.map_in_place(|&s| nco.mix(dc.feed(s)));
+            }
 
             // Decimate from SDR to baseband sample rate.
-            let len = self.decim.decim_in_place(&mut samples[..]);
+            let len = match self.resample {
+                Some(ref mut d) => {
+                    let mut len = 0;
 
-            // This is safe because the decimated length is less than the original length.
-            unsafe { samples.set_len(len); }
+                    // Each output is written at or before the input it came from.
+                    for idx in 0..BUF_SAMPLES {
+                        if let Some(s) = d.feed(samples[idx]) {
+                            samples[len] = s;
+                            len += 1;
+                        }
+                    }
 
-            // Apply bandpass filter to attenuate out-of-channel interference.
-            samples.map_in_place(|&s| self.bandpass.feed(s));
+                    len
+                },
+                None => self.decim.decim_in_place(&mut samples[..]),
+            };
 
-            notifier.throttle(|| {
-                // Calculate power assuming a "normalized" resistance.
-                let power = power_dbm(&samples[..], 1.0);
+            // This is safe because the decimated length is less than the original length.
+            unsafe { samples.set_len(len); }
 
-                self.hub.send(HubEvent::UpdateSignalPower(power))
-                    .expect("unable to send signal power");
-            });
+            self.demod.filter(&mut samples[..]);
 
             let mut baseband = pool.checkout().expect("unable to allocate baseband");
 
             // This is safe because each input sample produces exactly one output sample.
             unsafe { baseband.set_len(samples.len()); }
 
+            let mut stats = self.demod.demod(&samples[..], &mut baseband[..]);
+            stats.generation = chunk.generation;
+
+            if let Some((ref mut tap, ref flag)) = self.eye {
+                if !flag.load(Ordering::Relaxed) {
+                    tap.reset();
+                } else if let (Some(eye), Some(hub)) = (tap.feed(&baseband[..]),
+                                                        self.hub.as_ref()) {
+                    hub.send(HubEvent::State(StateEvent::UpdateEye(eye)))
+                        .expect("unable to send eye diagram");
+                }
+            }
+
+            if let Some(ref hub) = self.hub {
+                notifier.throttle(|| {
+                    hub.send(HubEvent::UpdateSignalPower(stats.power))
+                        .expect("unable to send signal power");
+                });
+            }
+
+            self.chan.send(RecvEvent::Baseband(baseband, stats))
+                .expect("unable to send baseband");
+        }
+
+        self.chan.send(RecvEvent::EndOfStream).expect("unable to send end of stream");
+    }
+}
+
+/// Filters and demodulates a 48kHz I/Q channel to C4FM baseband.
+pub struct Demodulator {
+    /// Channel-select lowpass filter.
+    bandpass: FIRFilter<BandpassFIR>,
+    /// Deemphasis lowpass filter.
+    deemph: FIRFilter<DeemphFIR>,
+    /// Demodulates FM signal.
+    demod: FmDemod,
+    /// Demodulates CQPSK signal, if the channel uses it.
+    cqpsk: Option<CqpskDemod>,
+    /// Detects whether a carrier is present.
+    squelch: Squelch,
+}
+
+impl Demodulator {
+    /// Create a new `Demodulator` for the given modulation with empty filter histories.
+    pub fn new(modulation: Modulation) -> Self {
+        Demodulator {
+            bandpass: FIRFilter::new(),
+            deemph: FIRFilter::new(),
+            demod: FmDemod::new(FM_DEVIATION, BASEBAND_SAMPLE_RATE),
+            cqpsk: match modulation {
+                Modulation::C4fm => None,
+                Modulation::Cqpsk => Some(CqpskDemod::new()),
+            },
+            squelch: Squelch::new(),
+        }
+    }
+
+    /// Apply the bandpass filter to the given samples in place, attenuating
+    /// out-of-channel interference.
+    pub fn filter(&mut self, samples: &mut [Complex32]) {
+        samples.map_in_place(|&s| self.bandpass.feed(s));
+    }
+
+    /// Demodulate the given filtered samples into the given baseband buffer, which must
+    /// have the same length, and measure the chunk.
+    pub fn demod(&mut self, samples: &[Complex32], baseband: &mut [f32]) -> ChunkStats {
+        match self.cqpsk {
+            // Symbol levels come out flat, so they skip deemphasis.
+            Some(ref mut cqpsk) => {
+                samples.iter()
+                       .map(|&s| cqpsk.feed(s))
+                       .collect_slice(&mut baseband[..]);
+            },
             // Demodulate FM signal to C4FM baseband.
-            samples.iter()
-                   .map(|&s| self.demod.feed(s))
-                   .collect_slice(&mut baseband[..]);
+            None => {
+                samples.iter()
+                       .map(|&s| self.demod.feed(s))
+                       .collect_slice(&mut baseband[..]);
+            },
+        }
 
-            // Apply deemphasis filter.
+        let dc = baseband.iter().fold(0.0, |s, &x| s + x) / baseband.len() as f32;
+
+        // Apply deemphasis filter.
+        if self.cqpsk.is_none() {
             baseband.map_in_place(|&s| self.deemph.feed(s));
+        }
 
-            self.chan.send(RecvEvent::Baseband(baseband))
-                .expect("unable to send baseband");
+        let snr = quality::snr_db(samples);
+
+        ChunkStats {
+            dc: dc,
+            // Calculate power assuming a "normalized" resistance.
+            power: power_dbm(samples, 1.0),
+            snr: snr,
+            spread: quality::symbol_spread(baseband),
+            carrier: self.squelch.feed(snr),
+            generation: 0,
         }
     }
 }
 
+/// Measurements of a chunk of demodulated samples.
+#[derive(Copy, Clone, Default)]
+pub struct ChunkStats {
+    /// Mean demodulator output before deemphasis, proportional to the offset of the
+    /// signal from the tuned frequency.
+    pub dc: f32,
+    /// Power (dBm) of the filtered channel.
+    pub power: f32,
+    /// Estimated SNR (dB) of the filtered channel.
+    pub snr: f32,
+    /// RMS distance (Hz) of demodulated symbols from the ideal deviations.
+    pub spread: f32,
+    /// Whether a carrier was present on the channel.
+    pub carrier: bool,
+    /// Tuning generation the samples were received at.
+    pub generation: usize,
+}
+
+/// Transform the given interleaved I/Q bytes to complex floating point samples, filling
+/// the given buffer, which must hold half as many samples as there are bytes.
+pub fn iq_samples(bytes: &[u8], samples: &mut [Complex32]) {
+    assert!(bytes.len() == samples.len() * 2);
+
+    // This is safe because it's transforming an array of N 8-bit words to an array of N/2
+    // 16-bit words.
+    let pairs = unsafe {
+        std::slice::from_raw_parts(bytes.as_ptr() as *const u16, samples.len())
+    };
+
+    pairs.iter()
+         .map(|&s| unsafe { *IQ.get_unchecked(s as usize) })
+         .collect_slice(samples);
+}
+
 /// Decimates from 240kHz to 48kHz.
 struct Decimate5;
 
@@ -0,0 +1,146 @@
+//! Lookup of RTL-SDR devices by USB serial number.
+//!
+//! Device indexes follow USB enumeration order, so they can change whenever dongles are
+//! plugged in or out, while serials (settable with `rtl_eeprom -s`) stay with the
+//! physical device.
+
+use std::ffi::CStr;
+use std::fmt;
+
+use libc::{c_char, c_int};
+
+#[link(name = "rtlsdr")]
+extern "C" {
+    fn rtlsdr_get_device_count() -> u32;
+    fn rtlsdr_get_device_usb_strings(index: u32, manufact: *mut c_char,
+                                     product: *mut c_char, serial: *mut c_char)
+        -> c_int;
+}
+
+/// Size of the buffers filled by `rtlsdr_get_device_usb_strings`.
+const USB_STRING_LEN: usize = 256;
+
+/// Get the USB serial of each attached device, in index order, or `None` for devices
+/// whose serial can't be read.
+pub fn serials() -> Vec<Option<String>> {
+    let count = unsafe { rtlsdr_get_device_count() };
+
+    (0..count).map(serial).collect()
+}
+
+/// Get the USB serial of the device with the given index.
+fn serial(index: u32) -> Option<String> {
+    let mut manufact = [0 as c_char; USB_STRING_LEN];
+    let mut product = [0 as c_char; USB_STRING_LEN];
+    let mut serial = [0 as c_char; USB_STRING_LEN];
+
+    // This is safe because each buffer is the size required by the library, which
+    // writes NUL-terminated strings into them.
+    let ret = unsafe {
+        rtlsdr_get_device_usb_strings(index, manufact.as_mut_ptr(), product.as_mut_ptr(),
+                                      serial.as_mut_ptr())
+    };
+
+    if ret != 0 {
+        return None;
+    }
+
+    let s = unsafe { CStr::from_ptr(serial.as_ptr()) };
+
+    Some(s.to_string_lossy().into_owned())
+}
+
+/// Reasons a device given on the command line couldn't be found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LookupError {
+    /// No device has the given serial or index.
+    NotFound,
+    /// Several devices share the given serial, with the contained indexes, so it doesn't
+    /// identify a single device.
+    Ambiguous(Vec<u32>),
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LookupError::NotFound => write!(f, "rtlsdr device not found"),
+            LookupError::Ambiguous(ref idxs) => {
+                let idxs = idxs.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+
+                write!(f, "serial is shared by rtlsdr devices {} (set unique serials \
+                           with rtl_eeprom -s)", idxs.join(", "))
+            },
+        }
+    }
+}
+
+/// Find the index of the device given on the command line, either by USB serial or by
+/// index, among devices with the given serials.
+///
+/// An exact serial match takes precedence, since serials are often numeric. A serial
+/// shared by several devices is rejected rather than picking one of them.
+pub fn find(spec: &str, serials: &[Option<String>]) -> Result<u32, LookupError> {
+    let matches = serials.iter().enumerate()
+        .filter(|&(_, s)| match *s {
+            Some(ref s) => s == spec,
+            None => false,
+        })
+        .map(|(idx, _)| idx as u32)
+        .collect::<Vec<u32>>();
+
+    match matches.len() {
+        0 => {},
+        1 => return Ok(matches[0]),
+        _ => return Err(LookupError::Ambiguous(matches)),
+    }
+
+    match spec.parse::<u32>() {
+        Ok(idx) if (idx as usize) < serials.len() => Ok(idx),
+        _ => Err(LookupError::NotFound),
+    }
+}
+
+/// Find the index of the attached device given on the command line.
+pub fn lookup(spec: &str) -> Result<u32, LookupError> {
+    find(spec, &serials()[..])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let serials = [
+            Some("00000001".to_string()),
+            None,
+            Some("p25ctl".to_string()),
+            Some("2".to_string()),
+        ];
+
+        assert_eq!(find("p25ctl", &serials[..]), Ok(2));
+        assert_eq!(find("00000001", &serials[..]), Ok(0));
+        assert_eq!(find("1", &serials[..]), Ok(1));
+        assert_eq!(find("0", &serials[..]), Ok(0));
+        // Serials win over indexes.
+        assert_eq!(find("2", &serials[..]), Ok(3));
+        assert_eq!(find("4", &serials[..]), Err(LookupError::NotFound));
+        assert_eq!(find("p25", &serials[..]), Err(LookupError::NotFound));
+        assert_eq!(find("", &serials[..]), Err(LookupError::NotFound));
+    }
+
+    #[test]
+    fn test_find_shared() {
+        // Stock dongles all ship with the same serial.
+        let serials = [
+            Some("00000001".to_string()),
+            Some("p25ctl".to_string()),
+            Some("00000001".to_string()),
+        ];
+
+        assert_eq!(find("00000001", &serials[..]),
+                   Err(LookupError::Ambiguous(vec![0, 2])));
+        assert_eq!(find("p25ctl", &serials[..]), Ok(1));
+        assert_eq!(find("2", &serials[..]), Ok(2));
+    }
+}
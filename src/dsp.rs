@@ -0,0 +1,463 @@
+//! General signal processing building blocks.
+
+use std::f32::consts::PI;
+
+use num::complex::Complex32;
+use num::traits::Zero;
+
+use consts::{SDR_SAMPLE_RATE, BASEBAND_SAMPLE_RATE};
+
+/// Cutoff (Hz) of the filter ahead of the final decimation, passing a whole P25 channel.
+const CHANNEL_CUTOFF: f32 = 12500.0;
+
+/// Numerically-controlled oscillator for shifting a signal in frequency.
+pub struct Nco {
+    /// Current phase (radians).
+    phase: f32,
+    /// Phase advance per sample (radians).
+    step: f32,
+}
+
+impl Nco {
+    /// Create a new `Nco` at the given frequency (cycles per sample).
+    pub fn new(freq: f32) -> Self {
+        Nco {
+            phase: 0.0,
+            step: 2.0 * PI * freq,
+        }
+    }
+
+    /// Change the oscillator frequency (cycles per sample), keeping the current phase.
+    pub fn set_freq(&mut self, freq: f32) {
+        self.step = 2.0 * PI * freq;
+    }
+
+    /// Shift the given sample by the oscillator frequency and advance the oscillator by
+    /// one sample.
+    pub fn mix(&mut self, s: Complex32) -> Complex32 {
+        let out = s * Complex32::from_polar(&1.0, &self.phase);
+
+        self.phase += self.step;
+
+        // Keep the phase small so it doesn't lose precision.
+        if self.phase > PI {
+            self.phase -= 2.0 * PI;
+        } else if self.phase < -PI {
+            self.phase += 2.0 * PI;
+        }
+
+        out
+    }
+}
+
+/// Removes the DC component of a signal, such as the spike from an SDR's LO leakage.
+pub struct DcBlocker {
+    /// Running estimate of the DC level.
+    mean: Complex32,
+    /// Fraction of each sample's difference from the estimate that's added to it.
+    alpha: f32,
+}
+
+impl DcBlocker {
+    /// Create a new `DcBlocker` that tracks the DC level with the given smoothing factor,
+    /// where smaller factors remove a narrower band around DC.
+    pub fn new(alpha: f32) -> Self {
+        DcBlocker {
+            mean: Complex32::zero(),
+            alpha: alpha,
+        }
+    }
+
+    /// Remove the current DC estimate from the given sample and update the estimate.
+    pub fn feed(&mut self, s: Complex32) -> Complex32 {
+        self.mean = self.mean + (s - self.mean) * self.alpha;
+        s - self.mean
+    }
+}
+
+/// Design a lowpass FIR filter with the given number of taps and cutoff frequency
+/// (fraction of the sample rate).
+///
+/// The filter is a Hamming-windowed sinc, normalized to unity gain at DC.
+pub fn lowpass(taps: usize, cutoff: f32) -> Vec<f32> {
+    let mid = (taps - 1) as f32 / 2.0;
+
+    let mut coefs: Vec<f32> = (0..taps).map(|n| {
+        let t = n as f32 - mid;
+
+        let sinc = if t == 0.0 {
+            2.0 * cutoff
+        } else {
+            (2.0 * PI * cutoff * t).sin() / (PI * t)
+        };
+
+        let window = 0.54 - 0.46 * (2.0 * PI * n as f32 / (taps - 1) as f32).cos();
+
+        sinc * window
+    }).collect();
+
+    let sum = coefs.iter().fold(0.0, |s, &c| s + c);
+
+    for c in coefs.iter_mut() {
+        *c /= sum;
+    }
+
+    coefs
+}
+
+/// FIR filter that keeps only every Nth output, computing only the outputs it keeps.
+pub struct FirDecimator {
+    /// Filter coefficients.
+    taps: Vec<f32>,
+    /// Recent input samples.
+    ///
+    /// Each sample is stored twice, `taps.len()` apart, so the most recent inputs are
+    /// always in one contiguous slice.
+    hist: Vec<Complex32>,
+    /// Index in the history of the oldest input.
+    idx: usize,
+    /// Decimation factor.
+    factor: usize,
+    /// Number of inputs remaining until the next output.
+    remain: usize,
+}
+
+impl FirDecimator {
+    /// Create a new `FirDecimator` with the given filter coefficients and decimation
+    /// factor.
+    pub fn new(taps: Vec<f32>, factor: usize) -> Self {
+        assert!(factor > 0);
+
+        FirDecimator {
+            hist: vec![Complex32::zero(); taps.len() * 2],
+            taps: taps,
+            idx: 0,
+            factor: factor,
+            remain: factor,
+        }
+    }
+
+    /// Feed in the given input sample, returning an output sample once every `factor`
+    /// inputs.
+    pub fn feed(&mut self, s: Complex32) -> Option<Complex32> {
+        let len = self.taps.len();
+
+        self.hist[self.idx] = s;
+        self.hist[self.idx + len] = s;
+        self.idx = (self.idx + 1) % len;
+
+        self.remain -= 1;
+
+        if self.remain > 0 {
+            return None;
+        }
+
+        self.remain = self.factor;
+
+        // Inputs from oldest to newest, so the newest lines up with the first tap.
+        let inputs = &self.hist[self.idx..self.idx + len];
+
+        Some(inputs.iter().zip(self.taps.iter().rev()).fold(Complex32::zero(),
+            |s, (&x, &c)| s + x * c))
+    }
+}
+
+/// Decimates I/Q samples from a multiple of the normal SDR sample rate down to the
+/// baseband sample rate.
+pub struct BasebandDecimator {
+    /// Decimates to the normal SDR rate, if the input is faster.
+    first: Option<FirDecimator>,
+    /// Decimates from the normal SDR rate to the baseband rate.
+    second: FirDecimator,
+}
+
+impl BasebandDecimator {
+    /// Create a new `BasebandDecimator` for input at the given sample rate (Hz).
+    pub fn new(rate: u32) -> Self {
+        assert!(rate % SDR_SAMPLE_RATE == 0);
+
+        let factor = (rate / SDR_SAMPLE_RATE) as usize;
+
+        BasebandDecimator {
+            // Keep well clear of the aliases folded in by the decimation.
+            first: if factor > 1 {
+                Some(FirDecimator::new(lowpass(8 * factor + 1, 0.4 / factor as f32),
+                                       factor))
+            } else {
+                None
+            },
+            second: FirDecimator::new(
+                lowpass(41, CHANNEL_CUTOFF / SDR_SAMPLE_RATE as f32),
+                (SDR_SAMPLE_RATE / BASEBAND_SAMPLE_RATE) as usize),
+        }
+    }
+
+    /// Feed in the given input sample, returning an output sample each time one is
+    /// produced at the baseband rate.
+    pub fn feed(&mut self, s: Complex32) -> Option<Complex32> {
+        let s = match self.first {
+            Some(ref mut d) => match d.feed(s) {
+                Some(s) => s,
+                None => return None,
+            },
+            None => s,
+        };
+
+        self.second.feed(s)
+    }
+}
+
+/// Splits I/Q samples into evenly spaced frequency bins, each filtered and decimated to
+/// twice the bin spacing, with the filtering shared across all bins.
+///
+/// This is a polyphase analysis filterbank oversampled by two. Each bin passes signals up
+/// to about the bin spacing from its center without aliasing, so a narrowband channel
+/// anywhere within half a spacing of a bin's center is passed whole by that bin.
+pub struct Filterbank {
+    /// Prototype lowpass filter coefficients.
+    taps: Vec<f32>,
+    /// Recent input samples, stored twice as in `FirDecimator`.
+    hist: Vec<Complex32>,
+    /// Index in the history of the oldest input.
+    idx: usize,
+    /// `e^(j2πr/bins)` for each `r` in `0..bins`.
+    twiddles: Vec<Complex32>,
+    /// Output of each polyphase branch at the most recent output.
+    branches: Vec<Complex32>,
+    /// Number of inputs remaining until the next output.
+    remain: usize,
+    /// Whether the most recent output is at an odd multiple of the decimation factor.
+    odd: bool,
+}
+
+impl Filterbank {
+    /// Create a new `Filterbank` with the given number of bins, which must be even.
+    pub fn new(bins: usize) -> Self {
+        assert!(bins > 0 && bins % 2 == 0);
+
+        // Neighboring bins overlap, so each passes the channels centered near the edges
+        // of its neighbors.
+        let taps = lowpass(8 * bins, 1.0 / bins as f32);
+
+        Filterbank {
+            hist: vec![Complex32::zero(); taps.len() * 2],
+            taps: taps,
+            idx: 0,
+            twiddles: (0..bins).map(|r| {
+                Complex32::from_polar(&1.0, &(2.0 * PI * r as f32 / bins as f32))
+            }).collect(),
+            branches: vec![Complex32::zero(); bins],
+            // Outputs line up with multiples of the decimation factor, starting with the
+            // first input.
+            remain: 1,
+            odd: true,
+        }
+    }
+
+    /// Number of bins.
+    pub fn bins(&self) -> usize {
+        self.branches.len()
+    }
+
+    /// Feed in the given input sample, returning `true` once every `bins / 2` inputs when
+    /// every bin has a new output.
+    pub fn feed(&mut self, s: Complex32) -> bool {
+        let len = self.taps.len();
+
+        self.hist[self.idx] = s;
+        self.hist[self.idx + len] = s;
+        self.idx = (self.idx + 1) % len;
+
+        self.remain -= 1;
+
+        if self.remain > 0 {
+            return false;
+        }
+
+        let bins = self.bins();
+
+        self.remain = bins / 2;
+        self.odd = !self.odd;
+
+        for b in self.branches.iter_mut() {
+            *b = Complex32::zero();
+        }
+
+        // Inputs from newest to oldest, lined up with the taps.
+        let inputs = self.hist[self.idx..self.idx + len].iter().rev();
+
+        for (n, (&x, &c)) in inputs.zip(self.taps.iter()).enumerate() {
+            let b = &mut self.branches[n % bins];
+            *b = *b + x * c;
+        }
+
+        true
+    }
+
+    /// Get the most recent output of the given bin, which is centered at `bin / bins`
+    /// cycles per input sample.
+    ///
+    /// Outputs are the same as if the input were shifted down by the bin's center
+    /// frequency, lowpass filtered, and decimated.
+    pub fn bin(&self, bin: usize) -> Complex32 {
+        let bins = self.bins();
+
+        let out = self.branches.iter().enumerate().fold(Complex32::zero(), |s, (r, &v)| {
+            s + v * self.twiddles[bin * r % bins]
+        });
+
+        // The shift is `e^(-jπ bin)` per output, since outputs are half the bins apart.
+        if self.odd && bin % 2 == 1 {
+            -out
+        } else {
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use num::complex::Complex32;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn test_lowpass() {
+        let taps = lowpass(31, 0.1);
+
+        assert_eq!(taps.len(), 31);
+        assert!((taps.iter().fold(0.0, |s, &c| s + c) - 1.0).abs() < 1e-5);
+
+        for i in 0..15 {
+            assert!((taps[i] - taps[30 - i]).abs() < 1e-6);
+        }
+
+        // Center tap is the largest.
+        assert!(taps.iter().all(|&c| c <= taps[15]));
+    }
+
+    #[test]
+    fn test_decimator() {
+        let mut d = FirDecimator::new(lowpass(21, 0.05), 5);
+
+        let out: Vec<Complex32> = (0..100)
+            .filter_map(|_| d.feed(Complex32::new(1.0, -1.0)))
+            .collect();
+
+        assert_eq!(out.len(), 20);
+
+        // DC passes with unity gain once the history fills.
+        for s in &out[5..] {
+            assert!((s.re - 1.0).abs() < 1e-4);
+            assert!((s.im + 1.0).abs() < 1e-4);
+        }
+
+        // A tone well above the cutoff is rejected.
+        let mut d = FirDecimator::new(lowpass(41, 0.05), 5);
+
+        let out: Vec<Complex32> = (0..400)
+            .map(|n| Complex32::from_polar(&1.0, &(0.3 * 2.0 * PI * n as f32)))
+            .filter_map(|s| d.feed(s))
+            .collect();
+
+        for s in &out[10..] {
+            assert!(s.norm() < 0.02);
+        }
+    }
+
+    #[test]
+    fn test_baseband_decimator() {
+        for &rate in &[240000, 1200000, 2400000] {
+            let mut d = BasebandDecimator::new(rate);
+            let n = rate as usize / 10;
+
+            let out: Vec<Complex32> = (0..n)
+                .filter_map(|_| d.feed(Complex32::new(0.5, 0.25)))
+                .collect();
+
+            assert_eq!(out.len(), BASEBAND_SAMPLE_RATE as usize / 10);
+
+            for s in &out[100..] {
+                assert!((s.re - 0.5).abs() < 1e-3);
+                assert!((s.im - 0.25).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_filterbank() {
+        let bins = 20;
+        let rate = 480000.0;
+        let spacing = rate / bins as f32;
+
+        // A tone 5kHz above the center of bin 3.
+        let tone = (3.0 * spacing + 5000.0) / rate;
+
+        let mut fb = Filterbank::new(bins);
+        let mut near = vec![];
+        let mut far = vec![];
+        let mut neg = vec![];
+
+        for n in 0..20000 {
+            let s = Complex32::from_polar(&1.0, &(2.0 * PI * tone * n as f32));
+
+            if fb.feed(s) {
+                near.push(fb.bin(3));
+                far.push(fb.bin(5));
+                neg.push(fb.bin(bins - 3));
+            }
+        }
+
+        assert_eq!(near.len(), 2000);
+
+        // The tone comes out of its bin at full strength, 5kHz from DC at the output
+        // rate.
+        let step = Complex32::from_polar(&1.0, &(2.0 * PI * 5000.0 / (2.0 * spacing)));
+
+        for w in near[100..].windows(2) {
+            assert!((w[0].norm() - 1.0).abs() < 0.01);
+            assert!((w[0] * step - w[1]).norm() < 0.02);
+        }
+
+        // Bins away from the tone reject it.
+        for s in far[100..].iter().chain(neg[100..].iter()) {
+            assert!(s.norm() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_dc_blocker() {
+        let mut dc = DcBlocker::new(0.01);
+
+        // A constant offset is removed.
+        let out: Vec<Complex32> = (0..2000)
+            .map(|_| dc.feed(Complex32::new(0.3, -0.2)))
+            .collect();
+
+        for s in &out[1000..] {
+            assert!(s.norm() < 1e-3);
+        }
+
+        // A tone is passed through.
+        let mut dc = DcBlocker::new(0.001);
+
+        for n in 0..1000 {
+            let s = Complex32::from_polar(&1.0, &(0.1 * 2.0 * PI * n as f32));
+            assert!((dc.feed(s) - s).norm() < 0.02);
+        }
+    }
+
+    #[test]
+    fn test_nco() {
+        // Shifting a tone by its own negative frequency brings it to DC.
+        let mut nco = Nco::new(-0.1);
+
+        for n in 0..1000 {
+            let s = Complex32::from_polar(&1.0, &(0.1 * 2.0 * PI * n as f32));
+            let out = nco.mix(s);
+
+            assert!((out.re - 1.0).abs() < 1e-2);
+            assert!(out.im.abs() < 1e-2);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Eye diagram and symbol level diagnostics.
+//!
+//! When a channel doesn't decode, the shape of the demodulated baseband usually shows
+//! why: levels that sit too close together point to a deviation mismatch, eyes that
+//! close between symbols to timing or filtering trouble, and levels that smear into
+//! each other to multipath or noise. This collects about a second of baseband at a time
+//! and summarizes it for display.
+
+use std;
+
+use consts::{BASEBAND_SAMPLE_RATE, FM_DEVIATION};
+use quality::{self, SAMPLES_PER_SYMBOL, LEVELS, MEAN_LEVEL};
+
+/// Number of baseband samples summarized in each capture.
+const WINDOW: usize = BASEBAND_SAMPLE_RATE as usize;
+
+/// Number of traces overlaid in the eye diagram.
+const TRACES: usize = 64;
+
+/// Width (Hz) of each histogram bin.
+const HIST_STEP: f32 = 100.0;
+
+/// Number of histogram bins, spread evenly around zero deviation.
+const HIST_BINS: usize = 64;
+
+/// Demodulated symbols decided as one of the four C4FM levels.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+pub struct EyeLevel {
+    /// Ideal deviation (Hz) of the level.
+    pub ideal: f32,
+    /// Number of symbols decided as this level.
+    pub count: u32,
+    /// Mean deviation (Hz) of the symbols.
+    pub mean: f32,
+    /// RMS distance (Hz) of the symbols from their mean.
+    pub spread: f32,
+}
+
+/// Summary of one capture of baseband.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Eye {
+    /// Overlaid two-symbol stretches of baseband (Hz), each centered on a symbol.
+    pub traces: Vec<Vec<f32>>,
+    /// Width (Hz) of each histogram bin.
+    #[serde(rename = "histogramStep")]
+    pub histogram_step: f32,
+    /// Number of symbols in each bin, from the most negative deviation to the most
+    /// positive, with zero deviation at the boundary between the two middle bins.
+    pub histogram: Vec<u32>,
+    /// The four levels, from the most negative deviation to the most positive.
+    pub levels: Vec<EyeLevel>,
+}
+
+/// Collects baseband into periodic eye captures.
+pub struct EyeTap {
+    /// Baseband collected for the current capture.
+    buf: Vec<f32>,
+}
+
+impl EyeTap {
+    /// Create a new `EyeTap` with an empty capture.
+    pub fn new() -> Self {
+        EyeTap {
+            buf: Vec::with_capacity(WINDOW),
+        }
+    }
+
+    /// Discard the current capture, such as when diagnostics are turned off.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+    }
+
+    /// Add the given baseband to the current capture, returning a summary if the capture
+    /// is complete.
+    pub fn feed(&mut self, baseband: &[f32]) -> Option<Eye> {
+        self.buf.extend_from_slice(baseband);
+
+        if self.buf.len() < WINDOW {
+            return None;
+        }
+
+        let eye = summarize(&self.buf[..]);
+        self.buf.clear();
+
+        Some(eye)
+    }
+}
+
+/// Summarize the given C4FM baseband.
+fn summarize(baseband: &[f32]) -> Eye {
+    let phase = quality::symbol_phase(baseband);
+
+    // Convert to deviation, assuming the demodulator outputs 1 at full deviation.
+    let dev = baseband.iter().map(|&x| x * FM_DEVIATION as f32).collect::<Vec<f32>>();
+
+    let symbols = dev.iter().enumerate()
+        .filter(|&(i, _)| i % SAMPLES_PER_SYMBOL == phase)
+        .map(|(_, &x)| x)
+        .collect::<Vec<f32>>();
+
+    Eye {
+        traces: traces(&dev[..], phase),
+        histogram_step: HIST_STEP,
+        histogram: histogram(&symbols[..]),
+        levels: levels(&symbols[..]),
+    }
+}
+
+/// Cut evenly spaced two-symbol traces, centered on symbols, from the given deviations
+/// with symbol centers at the given phase.
+fn traces(dev: &[f32], phase: usize) -> Vec<Vec<f32>> {
+    let len = 2 * SAMPLES_PER_SYMBOL + 1;
+
+    // Each trace starts a symbol before its center.
+    let starts = (phase..dev.len())
+        .filter(|&i| (i - phase) % SAMPLES_PER_SYMBOL == 0)
+        .filter(|&i| i >= SAMPLES_PER_SYMBOL && i - SAMPLES_PER_SYMBOL + len <= dev.len())
+        .map(|i| i - SAMPLES_PER_SYMBOL)
+        .collect::<Vec<usize>>();
+
+    let every = std::cmp::max(starts.len() / TRACES, 1);
+
+    starts.iter().enumerate()
+        .filter(|&(n, _)| n % every == 0)
+        .take(TRACES)
+        .map(|(_, &s)| dev[s..s + len].to_vec())
+        .collect()
+}
+
+/// Count the given symbol deviations (Hz) into histogram bins, clamping values beyond
+/// the outermost bins into them.
+fn histogram(symbols: &[f32]) -> Vec<u32> {
+    let mut hist = vec![0; HIST_BINS];
+    let half = (HIST_BINS / 2) as f32;
+
+    for &x in symbols {
+        let bin = (x / HIST_STEP + half).floor().max(0.0).min(HIST_BINS as f32 - 1.0);
+        hist[bin as usize] += 1;
+    }
+
+    hist
+}
+
+/// Decide each of the given symbol deviations (Hz) as one of the four levels and
+/// measure how they cluster.
+fn levels(symbols: &[f32]) -> Vec<EyeLevel> {
+    let count = symbols.len() as f32;
+    let mean = symbols.iter().fold(0.0, |s, &x| s + x) / count;
+    let mag = symbols.iter().fold(0.0, |s, &x| s + (x - mean).abs()) / count;
+
+    // Decide relative to the observed spread of symbols, so a deviation mismatch shows
+    // up as levels away from their ideal rather than as misdecided symbols.
+    let scale = if mag > 0.0 { MEAN_LEVEL / mag } else { 1.0 };
+
+    let decided = symbols.iter().map(|&x| {
+        let d = (x - mean) * scale;
+
+        (0..4).fold(0, |best, i| {
+            if (d - LEVELS[i]).abs() < (d - LEVELS[best]).abs() { i } else { best }
+        })
+    }).collect::<Vec<usize>>();
+
+    LEVELS.iter().enumerate().map(|(idx, &ideal)| {
+        let members = symbols.iter().zip(decided.iter())
+            .filter(|&(_, &d)| d == idx)
+            .map(|(&x, _)| x)
+            .collect::<Vec<f32>>();
+
+        if members.is_empty() {
+            return EyeLevel {
+                ideal: ideal,
+                ..EyeLevel::default()
+            };
+        }
+
+        let n = members.len() as f32;
+        let mean = members.iter().fold(0.0, |s, &x| s + x) / n;
+        let var = members.iter().fold(0.0, |s, &x| s + (x - mean) * (x - mean)) / n;
+
+        EyeLevel {
+            ideal: ideal,
+            count: members.len() as u32,
+            mean: mean,
+            spread: var.sqrt(),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f32::consts::PI;
+    use consts::FM_DEVIATION;
+    use quality::{SAMPLES_PER_SYMBOL, LEVELS};
+    use quality::test::noise;
+
+    #[test]
+    fn test_eye() {
+        // Symbols at 90% of the ideal deviation, with raised-cosine transitions over the
+        // first half of each symbol period.
+        let symbols = noise(5000).iter().map(|&x| {
+            LEVELS[((x + 1.0) * 2.0) as usize] * 0.9 / FM_DEVIATION as f32
+        }).collect::<Vec<f32>>();
+
+        let baseband = (0..WINDOW).map(|i| {
+            let n = (i + SAMPLES_PER_SYMBOL - 4) / SAMPLES_PER_SYMBOL;
+            let t = ((i + SAMPLES_PER_SYMBOL - 4) % SAMPLES_PER_SYMBOL) as f32 /
+                SAMPLES_PER_SYMBOL as f32;
+            let w = if t < 0.5 { 0.5 + 0.5 * (PI * t).cos() } else { 0.0 };
+
+            symbols[n] * (1.0 - w / 2.0) + symbols[n.saturating_sub(1)] * w / 2.0
+        }).collect::<Vec<f32>>();
+
+        let mut tap = EyeTap::new();
+        assert_eq!(tap.feed(&baseband[..WINDOW / 2]), None);
+
+        let eye = tap.feed(&baseband[WINDOW / 2..]).unwrap();
+
+        assert_eq!(eye.traces.len(), TRACES);
+        assert!(eye.traces.iter().all(|t| t.len() == 2 * SAMPLES_PER_SYMBOL + 1));
+
+        // Traces are centered on symbols, which sit at the ideal levels.
+        assert!(eye.traces.iter().all(|t| {
+            LEVELS.iter().any(|&l| (t[SAMPLES_PER_SYMBOL] - l * 0.9).abs() < 1.0)
+        }));
+
+        assert_eq!(eye.histogram.len(), HIST_BINS);
+        assert_eq!(eye.histogram.iter().sum::<u32>() as usize,
+                   WINDOW / SAMPLES_PER_SYMBOL);
+
+        // Outer levels land in the bins covering 1620Hz.
+        assert!(eye.histogram[HIST_BINS / 2 + 16] > 0);
+        assert!(eye.histogram[HIST_BINS / 2 - 17] > 0);
+
+        assert_eq!(eye.levels.len(), 4);
+
+        for (level, &ideal) in eye.levels.iter().zip(LEVELS.iter()) {
+            assert_eq!(level.ideal, ideal);
+            assert!(level.count > 0);
+            assert!((level.mean - ideal * 0.9).abs() < 1.0);
+            assert!(level.spread < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut tap = EyeTap::new();
+
+        assert_eq!(tap.feed(&vec![0.1; WINDOW - 1][..]), None);
+        tap.reset();
+        assert_eq!(tap.feed(&[0.1]), None);
+    }
+}
@@ -0,0 +1,371 @@
+//! Closed-loop tuner gain selection.
+//!
+//! Each tuner gain is tried on the control channel for a couple seconds while counting
+//! how many TSBKs pass their CRC, and the tuner is left at the gain that decoded the
+//! most. Since conditions change, the gains around the chosen one are periodically
+//! measured again.
+
+use std;
+
+use consts::BASEBAND_SAMPLE_RATE;
+
+/// Number of baseband samples discarded after each gain change, covering chunks already
+/// in flight at the previous gain.
+const SETTLE: usize = BASEBAND_SAMPLE_RATE as usize / 4;
+
+/// Number of baseband samples measured at each gain.
+const WINDOW: usize = BASEBAND_SAMPLE_RATE as usize * 2;
+
+/// Number of baseband samples to stay at the chosen gain before measuring again.
+const RECHECK: usize = BASEBAND_SAMPLE_RATE as usize * 600;
+
+/// Number of gains on each side of the chosen gain that are measured again.
+const NEIGHBORS: usize = 2;
+
+/// Channel power (dBm into 1 ohm) above which the samples are close enough to full scale
+/// that higher gains would clip.
+const OVERLOAD: f32 = 27.0;
+
+/// Decode results at one gain.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct GainMeasurement {
+    /// Tuner gain (tenths of dB).
+    pub gain: i32,
+    /// Number of TSBKs that passed their CRC.
+    pub decoded: u32,
+    /// Number of TSBKs that failed their CRC.
+    pub failed: u32,
+    /// Mean channel power (dBm into 1 ohm).
+    pub power: f32,
+}
+
+impl GainMeasurement {
+    /// Check if this measurement should be preferred over the given one: more decoded
+    /// TSBKs, then fewer failed, then lower gain.
+    fn better(&self, other: &GainMeasurement) -> bool {
+        (other.decoded, self.failed, self.gain) < (self.decoded, other.failed, other.gain)
+    }
+}
+
+/// Action requested after a measurement window.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GainStep {
+    /// Measurement of the previous gain, if one was taken.
+    pub measured: Option<GainMeasurement>,
+    /// Gain (tenths of dB) to switch the tuner to.
+    pub gain: i32,
+    /// Whether the gain was chosen to stay at, rather than to be measured.
+    pub settled: bool,
+}
+
+/// What the optimizer is currently doing.
+enum Mode {
+    /// Measuring the gain at the first index, with the sweep ending at the second.
+    Sweep(usize, usize),
+    /// Staying at the gain at the contained index.
+    Settled(usize),
+}
+
+/// Steps through the tuner gains to find the one with the best decode rate.
+pub struct GainOptimizer {
+    /// Gains (tenths of dB) that can be set, in increasing order.
+    gains: Vec<i32>,
+    /// Current mode.
+    mode: Mode,
+    /// Measurements taken during the current sweep.
+    results: Vec<GainMeasurement>,
+    /// Measurement the current gain was chosen from, if settled.
+    chosen: Option<GainMeasurement>,
+    /// Number of samples remaining to be discarded.
+    settle: usize,
+    /// Number of samples counted in the current window.
+    count: usize,
+    /// Sum of chunk power weighted by chunk length in the current window.
+    power: f32,
+    /// Number of TSBKs decoded in the current window.
+    decoded: u32,
+    /// Number of TSBKs failed in the current window.
+    failed: u32,
+}
+
+impl GainOptimizer {
+    /// Create a new `GainOptimizer` over the given tuner gains (tenths of dB), which
+    /// must not be empty.
+    pub fn new(mut gains: Vec<i32>) -> Self {
+        assert!(!gains.is_empty());
+
+        gains.sort();
+        gains.dedup();
+
+        let end = gains.len() - 1;
+
+        GainOptimizer {
+            gains: gains,
+            mode: Mode::Sweep(0, end),
+            results: vec![],
+            chosen: None,
+            settle: SETTLE,
+            count: 0,
+            power: 0.0,
+            decoded: 0,
+            failed: 0,
+        }
+    }
+
+    /// Get the gain (tenths of dB) the tuner should currently be at.
+    pub fn gain(&self) -> i32 {
+        match self.mode {
+            Mode::Sweep(idx, _) | Mode::Settled(idx) => self.gains[idx],
+        }
+    }
+
+    /// Discard the current window, such as after retuning.
+    pub fn reset(&mut self) {
+        self.settle = SETTLE;
+        self.count = 0;
+        self.power = 0.0;
+        self.decoded = 0;
+        self.failed = 0;
+    }
+
+    /// Continue the current window after time away from the channel, such as following a
+    /// voice call, discarding samples while the tuner settles back.
+    pub fn resume(&mut self) {
+        self.settle = SETTLE;
+    }
+
+    /// Count a received TSBK that passed its CRC if the flag is set, or failed it
+    /// otherwise.
+    pub fn tsbk(&mut self, valid: bool) {
+        if self.settle > 0 {
+            return;
+        }
+
+        if valid {
+            self.decoded += 1;
+        } else {
+            self.failed += 1;
+        }
+    }
+
+    /// Add a chunk with the given channel power (dBm) and number of samples.
+    ///
+    /// Return a step when the tuner should change gain.
+    pub fn feed(&mut self, power: f32, samples: usize) -> Option<GainStep> {
+        if self.settle > 0 {
+            self.settle = self.settle.saturating_sub(samples);
+            return None;
+        }
+
+        self.count += samples;
+        self.power += power * samples as f32;
+
+        match self.mode {
+            Mode::Settled(idx) => {
+                if self.count < RECHECK {
+                    return None;
+                }
+
+                // Without any decodes there's nothing to stay close to, so every gain
+                // is measured again.
+                let (start, end) = match self.chosen {
+                    Some(m) if m.decoded > 0 => (
+                        idx.saturating_sub(NEIGHBORS),
+                        std::cmp::min(idx + NEIGHBORS, self.gains.len() - 1),
+                    ),
+                    _ => (0, self.gains.len() - 1),
+                };
+
+                Some(self.switch(Mode::Sweep(start, end), None))
+            },
+            Mode::Sweep(idx, end) => {
+                if self.count < WINDOW {
+                    return None;
+                }
+
+                let m = GainMeasurement {
+                    gain: self.gains[idx],
+                    decoded: self.decoded,
+                    failed: self.failed,
+                    power: self.power / self.count as f32,
+                };
+
+                self.results.push(m);
+
+                // Higher gains would only push the signal further into clipping.
+                if idx < end && m.power < OVERLOAD {
+                    return Some(self.switch(Mode::Sweep(idx + 1, end), Some(m)));
+                }
+
+                let best = self.choose();
+                let pos = self.gains.iter().position(|&g| g == best.gain).unwrap();
+
+                self.chosen = Some(best);
+                self.results.clear();
+
+                Some(self.switch(Mode::Settled(pos), Some(m)))
+            },
+        }
+    }
+
+    /// Pick the best measurement from the current sweep.
+    fn choose(&self) -> GainMeasurement {
+        let first = self.results[0];
+
+        // If nothing could be decoded, the signal is most likely weak, so the highest
+        // gain that didn't clip gives the best chance.
+        if self.results.iter().all(|m| m.decoded == 0) {
+            return self.results.iter().fold(first, |best, m| {
+                if m.power < OVERLOAD && m.gain > best.gain { *m } else { best }
+            });
+        }
+
+        self.results.iter().fold(first, |best, m| {
+            if m.better(&best) { *m } else { best }
+        })
+    }
+
+    /// Change to the given mode, starting a new window.
+    fn switch(&mut self, mode: Mode, measured: Option<GainMeasurement>) -> GainStep {
+        let settled = match mode {
+            Mode::Settled(_) => true,
+            Mode::Sweep(..) => false,
+        };
+
+        self.mode = mode;
+        self.reset();
+
+        GainStep {
+            measured: measured,
+            gain: self.gain(),
+            settled: settled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Measure the current gain with the given number of decoded and failed TSBKs.
+    fn measure(opt: &mut GainOptimizer, power: f32, decoded: u32, failed: u32)
+        -> Option<GainStep>
+    {
+        assert_eq!(opt.feed(power, SETTLE), None);
+
+        for _ in 0..decoded {
+            opt.tsbk(true);
+        }
+
+        for _ in 0..failed {
+            opt.tsbk(false);
+        }
+
+        opt.feed(power, WINDOW)
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut opt = GainOptimizer::new(vec![200, 0, 100, 300, 400]);
+        assert_eq!(opt.gain(), 0);
+
+        // TSBKs during the settling time are ignored.
+        opt.tsbk(true);
+
+        let s = measure(&mut opt, -20.0, 10, 30).unwrap();
+        assert_eq!(s.measured.unwrap().decoded, 10);
+        assert_eq!(s.measured.unwrap().failed, 30);
+        assert_eq!(s.gain, 100);
+        assert!(!s.settled);
+
+        assert_eq!(measure(&mut opt, -10.0, 40, 2).unwrap().gain, 200);
+        assert_eq!(measure(&mut opt, 0.0, 40, 1).unwrap().gain, 300);
+        assert_eq!(measure(&mut opt, 10.0, 40, 0).unwrap().gain, 400);
+
+        let s = measure(&mut opt, 20.0, 5, 40).unwrap();
+        assert_eq!(s.gain, 300);
+        assert!(s.settled);
+        assert_eq!(opt.gain(), 300);
+
+        // Neighbors are checked again later.
+        assert_eq!(opt.feed(0.0, SETTLE), None);
+        assert_eq!(opt.feed(0.0, RECHECK - 1), None);
+        let s = opt.feed(0.0, 1).unwrap();
+        assert_eq!(s.measured, None);
+        assert_eq!(s.gain, 100);
+        assert!(!s.settled);
+
+        assert_eq!(measure(&mut opt, -10.0, 20, 0).unwrap().gain, 200);
+        assert_eq!(measure(&mut opt, -10.0, 30, 0).unwrap().gain, 300);
+        assert_eq!(measure(&mut opt, -10.0, 30, 0).unwrap().gain, 400);
+
+        // Ties go to the lower gain.
+        let s = measure(&mut opt, -10.0, 20, 0).unwrap();
+        assert_eq!(s.gain, 200);
+        assert!(s.settled);
+    }
+
+    #[test]
+    fn test_overload() {
+        let mut opt = GainOptimizer::new(vec![0, 100, 200, 300]);
+
+        assert_eq!(measure(&mut opt, 10.0, 5, 0).unwrap().gain, 100);
+
+        // Clipping ends the sweep early.
+        let s = measure(&mut opt, 28.0, 1, 20).unwrap();
+        assert_eq!(s.gain, 0);
+        assert!(s.settled);
+    }
+
+    #[test]
+    fn test_no_signal() {
+        let mut opt = GainOptimizer::new(vec![0, 100, 200]);
+
+        assert_eq!(measure(&mut opt, -30.0, 0, 0).unwrap().gain, 100);
+        assert_eq!(measure(&mut opt, -20.0, 0, 0).unwrap().gain, 200);
+
+        let s = measure(&mut opt, -10.0, 0, 0).unwrap();
+        assert_eq!(s.gain, 200);
+        assert!(s.settled);
+
+        // Everything is measured again.
+        opt.feed(0.0, SETTLE);
+        opt.feed(0.0, RECHECK);
+        assert_eq!(opt.gain(), 0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut opt = GainOptimizer::new(vec![0, 100]);
+
+        assert_eq!(opt.feed(0.0, SETTLE), None);
+        opt.tsbk(true);
+        assert_eq!(opt.feed(0.0, WINDOW / 2), None);
+
+        opt.reset();
+
+        let s = measure(&mut opt, 0.0, 0, 0).unwrap();
+        assert_eq!(s.measured.unwrap().decoded, 0);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut opt = GainOptimizer::new(vec![0, 100]);
+
+        assert_eq!(opt.feed(0.0, SETTLE), None);
+        opt.tsbk(true);
+        assert_eq!(opt.feed(0.0, WINDOW / 2), None);
+
+        opt.resume();
+
+        // TSBKs heard while settling back aren't counted.
+        opt.tsbk(true);
+
+        assert_eq!(opt.feed(0.0, SETTLE), None);
+        opt.tsbk(true);
+
+        let s = opt.feed(0.0, WINDOW / 2).unwrap();
+        assert_eq!(s.measured.unwrap().decoded, 2);
+        assert_eq!(s.gain, 100);
+    }
+}
@@ -1,12 +1,15 @@
 use std::convert::TryFrom;
 use std::io::{Write, ErrorKind};
 use std::net::SocketAddr;
-use std::os::unix::io::{RawFd, FromRawFd, IntoRawFd};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd, IntoRawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Sender, TryRecvError};
+use std::time::{Duration, Instant};
 use std;
 
 use arrayvec::ArrayVec;
-use mio::channel::{Receiver};
+use mio::channel::{self, Receiver};
 use mio::tcp::{TcpListener, TcpStream};
 use mio::unix::EventedFd;
 use mio::{Poll, PollOpt, Token, Event, Events, Ready};
@@ -23,12 +26,24 @@ use uhttp_status::StatusCode;
 use uhttp_uri::HttpResource;
 use uhttp_version::HttpVersion;
 
+use eye::Eye;
+use gainopt::GainMeasurement;
+use quality::Quality;
+use spectrum::Spectrum;
 use http;
-use recv::RecvEvent;
+use recv::{RecvEvent, RecordKind};
+use sdr::{ControlTaskEvent, SdrSettings};
 
 pub enum Route {
     Subscribe,
     CtlFreq,
+    Recording,
+    RecordingStart,
+    RecordingStop,
+    Sdr,
+    Quality,
+    Spectrum,
+    Eye,
 }
 
 impl<'a> TryFrom<HttpResource<'a>> for Route {
@@ -38,14 +53,29 @@ impl<'a> TryFrom<HttpResource<'a>> for Route {
         match r.path {
             "/subscribe" => Ok(Route::Subscribe),
             "/ctlfreq" => Ok(Route::CtlFreq),
+            "/recording" => Ok(Route::Recording),
+            "/recording/start" => Ok(Route::RecordingStart),
+            "/recording/stop" => Ok(Route::RecordingStop),
+            "/sdr" => Ok(Route::Sdr),
+            "/quality" => Ok(Route::Quality),
+            "/spectrum" => Ok(Route::Spectrum),
+            "/eye" => Ok(Route::Eye),
             _ => Err(StatusCode::NotFound),
         }
     }
 }
 
+/// Time (seconds) the eye diagram tap stays on after a client last turned it on or read
+/// it, so a client that goes away doesn't leave it running.
+const EYE_TIMEOUT: u64 = 30;
+
+/// Longest time (seconds) to wait for events before checking timeouts.
+const POLL_INTERVAL: u64 = 1;
+
 const CONNS: usize = 1 << 31;
 const EVENTS: usize = 1 << 30;
 const REQUEST: usize = 1 << 29;
+const REPLY: usize = 1 << 28;
 
 /// Allow 24 bits for file descriptors
 ///
@@ -64,6 +94,9 @@ pub enum HubToken {
     Events,
     /// Request stream with contained file descriptor.
     Request(RawFd),
+    /// Result of a request carried out by another task, to be answered on the stream
+    /// with the contained file descriptor.
+    Reply(RawFd),
 }
 
 impl From<HubToken> for Token {
@@ -71,7 +104,8 @@ impl From<HubToken> for Token {
         Token(match tok {
             HubToken::Conns => CONNS,
             HubToken::Events => EVENTS,
-            HubToken::Request(fd) => REQUEST | fd as usize
+            HubToken::Request(fd) => REQUEST | fd as usize,
+            HubToken::Reply(fd) => REPLY | fd as usize,
         })
     }
 }
@@ -82,6 +116,7 @@ impl From<Token> for HubToken {
             CONNS => HubToken::Conns,
             EVENTS => HubToken::Events,
             b if b & REQUEST != 0 => HubToken::Request(b as RawFd & FD_MASK),
+            b if b & REPLY != 0 => HubToken::Reply(b as RawFd & FD_MASK),
             _ => panic!("unknown token"),
         }
     }
@@ -92,6 +127,26 @@ impl HubToken {
         assert!(fd & !FD_MASK == 0);
         HubToken::Request(fd)
     }
+
+    pub fn for_reply(fd: RawFd) -> Self {
+        assert!(fd & !FD_MASK == 0);
+        HubToken::Reply(fd)
+    }
+}
+
+/// Request waiting for another task to carry it out before it's answered.
+struct Pending {
+    /// Stream to answer the request on.
+    stream: TcpStream,
+    /// Channel the result arrives on.
+    reply: Receiver<std::io::Result<()>>,
+    /// Message for the receiver once the request succeeds, if any.
+    then: Option<RecvEvent>,
+}
+
+/// Get the time an eye diagram tap refreshed now should turn off.
+fn eye_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(EYE_TIMEOUT)
 }
 
 pub struct HubTask {
@@ -101,10 +156,21 @@ pub struct HubTask {
     streamers: ArrayVec<[TcpStream; 4]>,
     chan: Receiver<HubEvent>,
     recv: Sender<RecvEvent>,
+    sdr: Sender<ControlTaskEvent>,
+    /// Flag that turns on the demodulator's eye diagram tap, if it has one.
+    eye: Option<Arc<AtomicBool>>,
+    /// Time the eye diagram tap turns off unless a client refreshes it, while it's on.
+    eye_deadline: Option<Instant>,
+    /// Requests waiting on other tasks, which are answered as results arrive so other
+    /// clients aren't held up.
+    pending: Vec<Pending>,
 }
 
 impl HubTask {
-    pub fn new(chan: Receiver<HubEvent>, recv: Sender<RecvEvent>, addr: &SocketAddr)
+    pub fn new(chan: Receiver<HubEvent>,
+               recv: Sender<RecvEvent>,
+               sdr: Sender<ControlTaskEvent>,
+               addr: &SocketAddr)
         -> std::io::Result<Self>
     {
         let socket = TcpListener::bind(addr)?;
@@ -122,22 +188,48 @@ impl HubTask {
             streamers: ArrayVec::new(),
             chan: chan,
             recv: recv,
+            sdr: sdr,
+            eye: None,
+            eye_deadline: None,
+            pending: vec![],
         })
     }
 
+    /// Let clients turn the eye diagram tap on and off with the given flag.
+    pub fn enable_eye(&mut self, flag: Arc<AtomicBool>) {
+        self.eye = Some(flag);
+    }
+
     pub fn run(&mut self) {
         let mut events = Events::with_capacity(32);
 
         loop {
-            self.events.poll(&mut events, None)
+            self.events.poll(&mut events, Some(Duration::from_secs(POLL_INTERVAL)))
                 .expect("unable to poll events");
 
             for event in events.iter() {
                 self.handle_event(event);
             }
+
+            self.expire_eye();
         }
     }
 
+    /// Turn the eye diagram tap off if no client has refreshed it in time.
+    fn expire_eye(&mut self) {
+        match self.eye_deadline {
+            Some(t) if Instant::now() >= t => {},
+            _ => return,
+        }
+
+        if let Some(ref flag) = self.eye {
+            flag.store(false, Ordering::Relaxed);
+        }
+
+        self.eye_deadline = None;
+        self.state.eye = None;
+    }
+
     fn handle_event(&mut self, e: Event) {
         match e.token().into() {
             HubToken::Conns =>
@@ -152,9 +244,67 @@ impl HubTask {
 
                 self.handle_stream(stream);
             },
+            HubToken::Reply(fd) => self.handle_reply(fd),
         }
     }
 
+    /// Answer the waiting request on the stream with the given file descriptor if its
+    /// result has arrived.
+    fn handle_reply(&mut self, fd: RawFd) {
+        let idx = match self.pending.iter().position(|p| p.stream.as_raw_fd() == fd) {
+            Some(idx) => idx,
+            None => return,
+        };
+
+        let result = match self.pending[idx].reply.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) =>
+                Err(std::io::Error::new(ErrorKind::Other, "no reply")),
+        };
+
+        let mut p = self.pending.swap_remove(idx);
+        let _ = self.events.deregister(&p.reply);
+
+        let status = match result {
+            Ok(()) => {
+                if let Some(then) = p.then.take() {
+                    self.recv.send(then).expect("unable to send follow-up event");
+                }
+
+                StatusCode::Ok
+            },
+            Err(ref e) if e.kind() == ErrorKind::InvalidInput => StatusCode::BadRequest,
+            Err(_) => StatusCode::InternalServerError,
+        };
+
+        let _ = http::send_status(&mut p.stream, status);
+    }
+
+    /// Answer the request on the given stream once a result arrives on the given
+    /// channel, sending the given message to the receiver if it succeeded.
+    fn await_reply(&mut self,
+                   stream: &mut TcpStream,
+                   reply: Receiver<std::io::Result<()>>,
+                   then: Option<RecvEvent>)
+        -> HttpResult<()>
+    {
+        let stream = try!(stream.try_clone()
+            .map_err(|_| StatusCode::InternalServerError));
+        let tok = HubToken::for_reply(stream.as_raw_fd());
+
+        try!(self.events.register(&reply, tok.into(), Ready::readable(),
+            PollOpt::edge()).map_err(|_| StatusCode::InternalServerError));
+
+        self.pending.push(Pending {
+            stream: stream,
+            reply: reply,
+            then: then,
+        });
+
+        Ok(())
+    }
+
     fn handle_conns(&mut self) -> Result<(), ()> {
         loop {
             let (stream, _) = match self.socket.accept() {
@@ -186,8 +336,8 @@ impl HubTask {
     }
 
     fn handle_message(&mut self, msg: HubEvent) {
-        if let HubEvent::State(sm) = msg {
-            self.state.update(sm);
+        if let HubEvent::State(ref sm) = msg {
+            self.state.update(sm.clone());
         }
 
         let mut keep = ArrayVec::<[TcpStream; 4]>::new();
@@ -209,7 +359,7 @@ impl HubTask {
     fn handle_stream(&mut self, mut s: TcpStream) {
         match self.handle_request(&mut s) {
             Ok(()) => {},
-            Err(e) => { http::send_status(&mut s, e).is_ok(); }
+            Err(e) => { let _ = http::send_status(&mut s, e); }
         }
     }
 
@@ -241,9 +391,9 @@ impl HubTask {
                 }
             },
             (Method::Get, Route::CtlFreq) => {
-                http::send_json(req.into_stream(), SerdeCtlFreq {
+                let _ = http::send_json(req.into_stream(), SerdeCtlFreq {
                     ctlfreq: self.state.ctlfreq,
-                }).is_ok();
+                });
 
                 Ok(())
             },
@@ -255,16 +405,111 @@ impl HubTask {
                 try!(self.recv.send(RecvEvent::SetControlFreq(msg.ctlfreq))
                     .map_err(|_| StatusCode::InternalServerError));
 
-                http::send_status(req.into_stream(), StatusCode::Ok).is_ok();
+                let _ = http::send_status(req.into_stream(), StatusCode::Ok);
+
+                Ok(())
+            },
+            (Method::Get, Route::Recording) => {
+                let _ = http::send_json(req.into_stream(), &self.state.recording);
+
+                Ok(())
+            },
+            (Method::Post, Route::RecordingStart) => {
+                let msg: SerdeRecordKind = req.read_json()?;
+                let (tx, rx) = channel::channel();
+
+                try!(self.recv.send(RecvEvent::StartRecording(msg.kind, tx))
+                    .map_err(|_| StatusCode::InternalServerError));
+
+                // Only report success once the recording file has been created.
+                self.await_reply(req.into_stream(), rx, None)
+            },
+            (Method::Post, Route::RecordingStop) => {
+                let msg: SerdeRecordKind = req.read_json()?;
+
+                try!(self.recv.send(RecvEvent::StopRecording(msg.kind))
+                    .map_err(|_| StatusCode::InternalServerError));
+
+                let _ = http::send_status(req.into_stream(), StatusCode::Ok);
+
+                Ok(())
+            },
+            (Method::Get, Route::Sdr) => {
+                let _ = http::send_json(req.into_stream(), &self.state.sdr);
+
+                Ok(())
+            },
+            (Method::Put, Route::Sdr) => {
+                let msg: SerdeSdrUpdate = req.read_json()?;
+                let events = try!(self.sdr_events(&msg));
+
+                let (tx, rx) = channel::channel();
+
+                try!(self.sdr.send(ControlTaskEvent::Apply(events, tx))
+                    .map_err(|_| StatusCode::InternalServerError));
+
+                // A manual gain replaces the optimized one, once it's been accepted.
+                let then = if msg.gain.is_some() || msg.agc.is_some() {
+                    Some(RecvEvent::StopGainOpt)
+                } else {
+                    None
+                };
+
+                // Only report success once the SDR has accepted every change, which
+                // waits out any reconnection in progress.
+                self.await_reply(req.into_stream(), rx, then)
+            },
+            (Method::Get, Route::Quality) => {
+                let _ = http::send_json(req.into_stream(), &self.state.quality);
+
+                Ok(())
+            },
+            (Method::Get, Route::Spectrum) => {
+                let _ = http::send_json(req.into_stream(), &self.state.spectrum);
+
+                Ok(())
+            },
+            (Method::Get, Route::Eye) => {
+                let flag = try!(self.eye.as_ref().ok_or(StatusCode::NotFound));
+                let enabled = flag.load(Ordering::Relaxed);
+
+                // A client still reading captures keeps the tap on.
+                if enabled {
+                    self.eye_deadline = Some(eye_deadline());
+                }
+
+                let _ = http::send_json(req.into_stream(), SerdeEyeState {
+                    enabled: enabled,
+                    capture: self.state.eye.clone(),
+                });
+
+                Ok(())
+            },
+            (Method::Put, Route::Eye) => {
+                let msg: SerdeEyeUpdate = req.read_json()?;
+                let flag = try!(self.eye.as_ref().ok_or(StatusCode::NotFound));
+
+                flag.store(msg.enabled, Ordering::Relaxed);
+
+                if msg.enabled {
+                    self.eye_deadline = Some(eye_deadline());
+                } else {
+                    self.eye_deadline = None;
+
+                    // Old captures would be mistaken for current ones once turned on.
+                    self.state.eye = None;
+                }
+
+                let _ = http::send_status(req.into_stream(), StatusCode::Ok);
 
                 Ok(())
             },
             (Method::Options, _) => {
                 let mut h = HeaderLines::new(req.into_stream());
 
-                http::send_head(&mut h, StatusCode::Ok).is_ok();
-                write!(h.line(), "Access-Control-Allow-Methods: GET, PUT").is_ok();
-                write!(h.line(), "Access-Control-Allow-Headers: Content-Type").is_ok();
+                let _ = http::send_head(&mut h, StatusCode::Ok);
+                let _ = write!(h.line(), "Access-Control-Allow-Methods: GET, PUT, POST");
+                let _ = write!(h.line(), "Access-Control-Allow-Headers: Content-Type");
 
                 Ok(())
             },
@@ -272,6 +517,46 @@ impl HubTask {
         }
     }
 
+    /// Validate the given settings update and convert it to SDR messages.
+    fn sdr_events(&self, msg: &SerdeSdrUpdate) -> HttpResult<Vec<ControlTaskEvent>> {
+        let mut events = vec![];
+
+        if let Some(gain) = msg.gain {
+            let valid = match self.state.sdr {
+                Some(ref s) => s.gains.contains(&gain),
+                None => false,
+            };
+
+            if !valid {
+                return Err(StatusCode::BadRequest);
+            }
+
+            events.push(ControlTaskEvent::SetGain(gain));
+        }
+
+        if let Some(agc) = msg.agc {
+            events.push(ControlTaskEvent::SetAgc(agc));
+        }
+
+        if let Some(ppm) = msg.ppm {
+            events.push(ControlTaskEvent::SetPpm(ppm));
+        }
+
+        if let Some(mode) = msg.direct_sampling {
+            if mode > 2 {
+                return Err(StatusCode::BadRequest);
+            }
+
+            events.push(ControlTaskEvent::SetDirectSampling(mode));
+        }
+
+        if let Some(on) = msg.bias_tee {
+            events.push(ControlTaskEvent::SetBiasTee(on));
+        }
+
+        Ok(events)
+    }
+
     fn start_stream(&self, s: &mut TcpStream) -> std::io::Result<()> {
         let mut h = HeaderLines::new(s);
 
@@ -290,12 +575,40 @@ impl HubTask {
 
             State(UpdateChannelParams(_)) => Ok(()),
 
+            State(UpdateRecording(ref r)) => SerdeEvent::new("recording", r).write(s),
+
+            State(UpdateSdr(ref sdr)) => SerdeEvent::new("sdr", sdr).write(s),
+
+            State(UpdateQuality(q)) => SerdeEvent::new("quality", q).write(s),
+
+            State(UpdateSpectrum(ref sp)) => SerdeEvent::new("spectrum", sp).write(s),
+
+            State(UpdateEye(ref eye)) => SerdeEvent::new("eye", eye).write(s),
+
             UpdateCurFreq(f) => SerdeEvent::new("curFreq", f).write(s),
 
             UpdateTalkGroup(tg) => SerdeEvent::new("talkGroup", tg).write(s),
 
             UpdateSignalPower(p) => SerdeEvent::new("sigPower", p).write(s),
 
+            UpdateVoiceTuner(t, grant) =>
+                SerdeEvent::new("voiceTuner", SerdeVoiceTuner::new(t, grant)).write(s),
+
+            UpdateAfc(error, correction) => SerdeEvent::new("afc", SerdeAfc {
+                error: error,
+                correction: correction,
+            }).write(s),
+
+            UpdateGainMeasurement(m) => SerdeEvent::new("gainMeasurement", m).write(s),
+
+            UpdateRetuneLatency(ms) => SerdeEvent::new("retuneLatency", ms).write(s),
+
+            UpdateSdrStatus(connected, attempts) =>
+                SerdeEvent::new("sdrStatus", SerdeSdrStatus {
+                    connected: connected,
+                    attempts: attempts,
+                }).write(s),
+
             // If this event has been received, the TSBK is valid with a known opcode.
             TrunkingControl(tsbk) => match tsbk.opcode().unwrap() {
                 TsbkOpcode::RfssStatusBroadcast =>
@@ -356,19 +669,49 @@ pub enum HubEvent {
     UpdateCurFreq(u32),
     UpdateTalkGroup(TalkGroup),
     UpdateSignalPower(f32),
+    /// The voice tuner with the given index started following the given channel
+    /// frequency and talkgroup, or became free.
+    UpdateVoiceTuner(usize, Option<(u32, TalkGroup)>),
+    /// The frequency correction loop measured the given tuning error (Hz) and is
+    /// applying the given correction (ppm).
+    UpdateAfc(f32, f32),
+    /// The gain optimizer finished measuring a gain.
+    UpdateGainMeasurement(GainMeasurement),
+    /// The first samples after a retune arrived the given time (ms) after it was
+    /// requested.
+    UpdateRetuneLatency(f32),
+    /// The SDR is connected if the flag is set, or was lost otherwise, after the given
+    /// number of attempts to reopen it.
+    UpdateSdrStatus(bool, u32),
     TrunkingControl(TsbkFields),
     LinkControl(LinkControlFields),
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub enum StateEvent {
     UpdateCtlFreq(u32),
     UpdateChannelParams(TsbkFields),
+    UpdateRecording(RecordingState),
+    UpdateSdr(SdrSettings),
+    /// A receiver finished measuring signal quality.
+    UpdateQuality(Quality),
+    /// A new spectrum frame of the control channel SDR was computed.
+    UpdateSpectrum(Spectrum),
+    /// The eye diagram tap finished a capture.
+    UpdateEye(Eye),
 }
 
 pub struct State {
     ctlfreq: u32,
     channels: ChannelParamsMap,
+    recording: RecordingState,
+    sdr: Option<SdrSettings>,
+    /// Most recent quality report from each receiver, ordered by tuner.
+    quality: Vec<Quality>,
+    /// Most recent spectrum frame, if spectrum analysis is enabled.
+    spectrum: Option<Spectrum>,
+    /// Most recent eye diagram capture, if the tap is on.
+    eye: Option<Eye>,
 }
 
 impl Default for State {
@@ -376,6 +719,11 @@ impl Default for State {
         State {
             ctlfreq: std::u32::MAX,
             channels: ChannelParamsMap::default(),
+            recording: RecordingState::default(),
+            sdr: None,
+            quality: vec![],
+            spectrum: None,
+            eye: None,
         }
     }
 }
@@ -388,15 +736,64 @@ impl State {
             UpdateCtlFreq(f) => self.ctlfreq = f,
             UpdateChannelParams(tsbk) =>
                 self.channels.update(&fields::ChannelParamsUpdate::new(tsbk.payload())),
+            UpdateRecording(r) => self.recording = r,
+            UpdateSdr(sdr) => self.sdr = Some(sdr),
+            UpdateQuality(q) => {
+                match self.quality.iter().position(|r| r.tuner >= q.tuner) {
+                    Some(idx) if self.quality[idx].tuner == q.tuner =>
+                        self.quality[idx] = q,
+                    Some(idx) => self.quality.insert(idx, q),
+                    None => self.quality.push(q),
+                }
+            },
+            UpdateSpectrum(sp) => self.spectrum = Some(sp),
+            UpdateEye(eye) => self.eye = Some(eye),
         }
     }
 }
 
+/// Paths of the recordings currently being written, if any.
+#[derive(Serialize, Clone, Default)]
+pub struct RecordingState {
+    pub baseband: Option<String>,
+    pub iq: Option<String>,
+}
+
 #[derive(Deserialize, Serialize)]
 struct SerdeCtlFreq {
     ctlfreq: u32,
 }
 
+#[derive(Deserialize)]
+struct SerdeRecordKind {
+    kind: RecordKind,
+}
+
+#[derive(Deserialize)]
+struct SerdeSdrUpdate {
+    #[serde(default)]
+    gain: Option<i32>,
+    #[serde(default)]
+    agc: Option<bool>,
+    #[serde(default)]
+    ppm: Option<i32>,
+    #[serde(rename = "directSampling", default)]
+    direct_sampling: Option<u32>,
+    #[serde(rename = "biasTee", default)]
+    bias_tee: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct SerdeEyeUpdate {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct SerdeEyeState {
+    enabled: bool,
+    capture: Option<Eye>,
+}
+
 #[derive(Serialize)]
 struct SerdeEvent<T: Serialize> {
     event: &'static str,
@@ -419,6 +816,35 @@ impl<T: Serialize> SerdeEvent<T> {
     }
 }
 
+#[derive(Serialize, Clone, Copy)]
+pub struct SerdeAfc {
+    error: f32,
+    correction: f32,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct SerdeSdrStatus {
+    connected: bool,
+    attempts: u32,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct SerdeVoiceTuner {
+    tuner: usize,
+    freq: Option<u32>,
+    talkgroup: Option<TalkGroup>,
+}
+
+impl SerdeVoiceTuner {
+    pub fn new(tuner: usize, grant: Option<(u32, TalkGroup)>) -> Self {
+        SerdeVoiceTuner {
+            tuner: tuner,
+            freq: grant.map(|(f, _)| f),
+            talkgroup: grant.map(|(_, tg)| tg),
+        }
+    }
+}
+
 #[derive(Serialize, Clone, Copy)]
 pub struct SerdeRfssStatus {
     area: u8,
@@ -0,0 +1,175 @@
+//! Control channel hunting.
+//!
+//! Sites can move their control channel to an alternate frequency at any time, such as
+//! when a repeater fails, and a receiver parked on the old frequency would hear nothing
+//! from then on. The receiver instead keeps a list of candidate frequencies, from the
+//! command line and from the alternates the site itself announces, and when valid
+//! trunking messages stop arriving it steps through the candidates until one of them
+//! yields messages again.
+
+use consts::BASEBAND_SAMPLE_RATE;
+
+/// Number of baseband samples without a valid TSBK after which a control channel that
+/// was being decoded is considered lost.
+const LOCK_TIMEOUT: usize = BASEBAND_SAMPLE_RATE as usize * 5;
+
+/// Number of baseband samples to wait for a valid TSBK on a candidate before moving to
+/// the next one.
+const DWELL: usize = BASEBAND_SAMPLE_RATE as usize * 2;
+
+/// Largest number of candidate frequencies tracked, which bounds how long a full pass
+/// over them can take.
+const MAX_CANDIDATES: usize = 16;
+
+/// Chooses which candidate frequency to receive the control channel on.
+pub struct ControlHunter {
+    /// Candidate frequencies (Hz), in the order they're tried.
+    freqs: Vec<u32>,
+    /// Index of the current candidate.
+    cur: usize,
+    /// Whether a valid TSBK has been received on the current candidate.
+    locked: bool,
+    /// Number of samples received on the current candidate since its last valid TSBK.
+    idle: usize,
+}
+
+impl ControlHunter {
+    /// Create a new `ControlHunter` starting on the given frequency (Hz).
+    pub fn new(freq: u32) -> Self {
+        ControlHunter {
+            freqs: vec![freq],
+            cur: 0,
+            locked: false,
+            idle: 0,
+        }
+    }
+
+    /// Get the current candidate frequency (Hz).
+    pub fn current(&self) -> u32 {
+        self.freqs[self.cur]
+    }
+
+    /// Add the given frequency (Hz) to the end of the candidates, if it isn't already
+    /// one and there's room for it.
+    pub fn add(&mut self, freq: u32) {
+        if self.freqs.len() < MAX_CANDIDATES && !self.freqs.contains(&freq) {
+            self.freqs.push(freq);
+        }
+    }
+
+    /// Switch to the given frequency (Hz), such as one chosen by the user, adding it to
+    /// the candidates if needed.
+    pub fn select(&mut self, freq: u32) {
+        self.cur = match self.freqs.iter().position(|&f| f == freq) {
+            Some(idx) => idx,
+            None => {
+                // Make room by dropping the last candidate, which was added most
+                // recently.
+                if self.freqs.len() == MAX_CANDIDATES {
+                    self.freqs.pop();
+                }
+
+                self.freqs.push(freq);
+                self.freqs.len() - 1
+            },
+        };
+
+        self.locked = false;
+        self.idle = 0;
+    }
+
+    /// Restart the timeout, such as after the receiver has been away from the control
+    /// channel.
+    pub fn restart(&mut self) {
+        self.idle = 0;
+    }
+
+    /// Mark that a valid TSBK was received on the current candidate.
+    pub fn valid(&mut self) {
+        self.locked = true;
+        self.idle = 0;
+    }
+
+    /// Advance the timeout by the given number of samples received on the current
+    /// candidate, returning the next candidate frequency (Hz) if it's time to move on.
+    pub fn feed(&mut self, samples: usize) -> Option<u32> {
+        self.idle += samples;
+
+        let timeout = if self.locked { LOCK_TIMEOUT } else { DWELL };
+
+        if self.idle < timeout {
+            return None;
+        }
+
+        self.idle = 0;
+
+        // With nowhere else to go, keep waiting on the only candidate.
+        if self.freqs.len() == 1 {
+            return None;
+        }
+
+        self.cur = (self.cur + 1) % self.freqs.len();
+        self.locked = false;
+
+        Some(self.current())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hunt() {
+        let mut h = ControlHunter::new(851000000);
+        assert_eq!(h.current(), 851000000);
+
+        // A lone candidate is never left.
+        assert_eq!(h.feed(LOCK_TIMEOUT * 2), None);
+
+        h.add(852000000);
+        h.add(851000000);
+        h.add(853000000);
+
+        // Locked channels get the longer timeout.
+        h.valid();
+        assert_eq!(h.feed(DWELL), None);
+        assert_eq!(h.feed(LOCK_TIMEOUT - DWELL - 1), None);
+        assert_eq!(h.feed(1), Some(852000000));
+
+        // Candidates that never decode are passed over quickly, wrapping around.
+        assert_eq!(h.feed(DWELL), Some(853000000));
+        assert_eq!(h.feed(DWELL), Some(851000000));
+
+        h.valid();
+        assert_eq!(h.feed(DWELL), None);
+
+        h.restart();
+        assert_eq!(h.feed(LOCK_TIMEOUT - 1), None);
+        h.valid();
+        assert_eq!(h.feed(LOCK_TIMEOUT - 1), None);
+
+        h.select(853000000);
+        assert_eq!(h.current(), 853000000);
+        assert_eq!(h.feed(DWELL), Some(851000000));
+
+        h.select(854000000);
+        assert_eq!(h.current(), 854000000);
+        assert_eq!(h.feed(DWELL), Some(851000000));
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut h = ControlHunter::new(0);
+
+        for f in 1..MAX_CANDIDATES as u32 * 2 {
+            h.add(f);
+        }
+
+        assert_eq!(h.freqs.len(), MAX_CANDIDATES);
+
+        h.select(1000);
+        assert_eq!(h.freqs.len(), MAX_CANDIDATES);
+        assert_eq!(h.current(), 1000);
+    }
+}
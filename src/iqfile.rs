@@ -0,0 +1,493 @@
+//! Recorded I/Q sample files.
+
+use std::fs::File;
+use std::io::{Read, Write, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+use std;
+
+use chrono::UTC;
+
+use consts::{BUF_BYTES, BUF_SAMPLES};
+use sdr::{SampleSource, SdrControl, SdrSettings, TapEvent};
+use sigmf;
+
+/// Reads interleaved unsigned 8-bit I/Q samples, as written by `rtl_sdr` (the `.cu8`
+/// format), from a stream.
+///
+/// Chunks are paced at the SDR sample rate so the rest of the pipeline sees the same
+/// timing as with a live SDR.
+pub struct IqFileSource<R: Read> {
+    /// Stream to read samples from.
+    stream: R,
+    /// Sample rate (Hz) the samples were captured at.
+    rate: u32,
+    /// Start sample and center frequency (Hz) of each recorded capture segment, where
+    /// segments with an unknown frequency match any tuning.
+    segments: Vec<(u64, Option<u32>)>,
+    /// Frequency (Hz) the emulated tuner is set to.
+    tuned: Arc<AtomicUsize>,
+}
+
+impl<R: Read> IqFileSource<R> {
+    /// Create a new `IqFileSource` over the given stream, which is assumed to have been
+    /// captured on a single frequency at the given sample rate (Hz).
+    pub fn new(stream: R, rate: u32) -> Self {
+        IqFileSource {
+            stream: stream,
+            rate: rate,
+            segments: vec![],
+            tuned: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Create a new `IqFileSource` over the given stream, which was captured at the given
+    /// sample rate (Hz) with the SDR hopping between the given segments, along with a
+    /// control for retuning it.
+    ///
+    /// Retunes are emulated: samples are only passed through while the control is tuned
+    /// to the frequency they were captured on, and are otherwise replaced with silence.
+    pub fn with_captures(stream: R, rate: u32, captures: &[sigmf::Capture])
+        -> (Self, ReplayControl)
+    {
+        let mut segments = vec![];
+
+        for c in captures {
+            // Segments without a frequency continue the previous one.
+            if let Some(freq) = c.frequency {
+                segments.push((c.sample_start, Some(freq as u32)));
+            }
+        }
+
+        // Samples before the first segment were captured on an unknown frequency, so
+        // they're passed through whatever the tuning.
+        match segments.first() {
+            Some(&(0, _)) => {},
+            _ => segments.insert(0, (0, None)),
+        }
+
+        let tuned = Arc::new(AtomicUsize::new(0));
+
+        (IqFileSource {
+            stream: stream,
+            rate: rate,
+            segments: segments,
+            tuned: tuned.clone(),
+        }, ReplayControl {
+            tuned: tuned,
+        })
+    }
+
+    /// Silence the samples in the given chunk, which starts at the given sample index,
+    /// that were captured on a known frequency other than the one currently tuned to.
+    fn mask(&self, buf: &mut [u8], start: u64) {
+        let tuned = self.tuned.load(Ordering::Relaxed) as u32;
+        let end = start + (buf.len() / 2) as u64;
+
+        for (idx, &(seg_start, freq)) in self.segments.iter().enumerate() {
+            match freq {
+                Some(f) if f != tuned => {},
+                _ => continue,
+            }
+
+            let seg_end = match self.segments.get(idx + 1) {
+                Some(&(s, _)) => s,
+                None => std::u64::MAX,
+            };
+
+            let lo = std::cmp::max(seg_start, start);
+            let hi = std::cmp::min(seg_end, end);
+
+            if lo >= hi {
+                continue;
+            }
+
+            // Unsigned samples are centered at 127.5, so this is close to zero.
+            for b in &mut buf[(lo - start) as usize * 2..(hi - start) as usize * 2] {
+                *b = 128;
+            }
+        }
+    }
+}
+
+impl<R: Read> SampleSource for IqFileSource<R> {
+    fn read_chunks<F: FnMut(&[u8])>(&mut self, mut cb: F) -> std::io::Result<()> {
+        let mut buf = vec![0; BUF_BYTES];
+        let period = chunk_period(self.rate);
+        let start = Instant::now();
+        let mut chunks = 0u64;
+
+        loop {
+            match self.stream.read_exact(&mut buf[..]) {
+                Ok(()) => {},
+                // Any trailing partial chunk is dropped.
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+
+            if !self.segments.is_empty() {
+                self.mask(&mut buf[..], chunks * BUF_SAMPLES as u64);
+            }
+
+            chunks += 1;
+
+            let deadline = start + period * chunks as u32;
+            let now = Instant::now();
+
+            if deadline > now {
+                thread::sleep(deadline - now);
+            }
+
+            cb(&buf[..]);
+        }
+    }
+}
+
+/// Emulated tuner for replaying a recording captured across several frequencies.
+pub struct ReplayControl {
+    /// Frequency (Hz) the emulated tuner is set to.
+    tuned: Arc<AtomicUsize>,
+}
+
+impl SdrControl for ReplayControl {
+    fn set_center_freq(&mut self, freq: u32) -> std::io::Result<()> {
+        self.tuned.store(freq as usize, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_sample_rate(&mut self, _: u32) -> std::io::Result<()> { Ok(()) }
+    fn set_ppm(&mut self, _: i32) -> std::io::Result<()> { Ok(()) }
+    fn set_tuner_gain(&mut self, _: i32) -> std::io::Result<()> { Ok(()) }
+    fn enable_agc(&mut self) -> std::io::Result<()> { Ok(()) }
+    fn gains(&mut self) -> Vec<i32> { vec![] }
+    fn emulated(&self) -> bool { true }
+}
+
+/// Handle for starting and stopping I/Q recordings made by an `IqRecordTask`.
+#[derive(Clone)]
+pub struct IqRecorder {
+    /// State shared with the recording task.
+    state: Arc<Mutex<RecorderState>>,
+    /// Global metadata written into each recording, with the SDR settings filled in as
+    /// the recording starts.
+    global: sigmf::Global,
+    /// Whether a recording is in progress, so raw chunks are only tapped while needed.
+    active: Arc<AtomicBool>,
+}
+
+impl IqRecorder {
+    /// Create a new `IqRecorder` describing recordings with the given global metadata,
+    /// along with the task that writes them and receives raw SDR events from the given
+    /// channel.
+    pub fn new(global: sigmf::Global, events: Receiver<TapEvent>)
+        -> (Self, IqRecordTask)
+    {
+        let state = Arc::new(Mutex::new(RecorderState {
+            tuned: None,
+            settings: None,
+            recording: None,
+        }));
+
+        (IqRecorder {
+            state: state.clone(),
+            global: global,
+            active: Arc::new(AtomicBool::new(false)),
+        }, IqRecordTask {
+            state: state,
+            events: events,
+        })
+    }
+
+    /// Start recording into the SigMF recording at the given base path, replacing any
+    /// current recording.
+    pub fn start<P: AsRef<Path>>(&self, base: P) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut rec = try!(IqRecording::create(base, self.global.clone(),
+                                               state.settings.clone()));
+
+        if let Some(freq) = state.tuned {
+            rec.start_capture(freq);
+        }
+
+        if let Some(mut prev) = state.recording.take() {
+            prev.finish();
+        }
+
+        state.recording = Some(rec);
+        self.active.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Stop any current recording.
+    pub fn stop(&self) {
+        self.active.store(false, Ordering::Relaxed);
+
+        if let Some(mut rec) = self.state.lock().unwrap().recording.take() {
+            rec.finish();
+        }
+    }
+
+    /// Get the flag that's set while a recording is in progress, for gating the tap that
+    /// feeds raw chunks to the recording task.
+    pub fn active(&self) -> Arc<AtomicBool> {
+        self.active.clone()
+    }
+}
+
+/// State shared between an `IqRecorder` and its `IqRecordTask`.
+struct RecorderState {
+    /// Most recent center frequency (Hz) of the SDR.
+    tuned: Option<u32>,
+    /// Most recent SDR settings and fine frequency correction (ppm).
+    settings: Option<(SdrSettings, f32)>,
+    /// Current recording, if any.
+    recording: Option<IqRecording>,
+}
+
+/// Records the raw SDR stream while an `IqRecorder` has a recording started.
+pub struct IqRecordTask {
+    /// State shared with the recorder handle.
+    state: Arc<Mutex<RecorderState>>,
+    /// Channel for raw chunks, retunes, and setting changes.
+    events: Receiver<TapEvent>,
+}
+
+impl IqRecordTask {
+    /// Begin handling events, blocking the current thread.
+    pub fn run(&mut self) {
+        while let Ok(event) = self.events.recv() {
+            let mut state = self.state.lock().unwrap();
+
+            match event {
+                TapEvent::Samples(chunk) => if let Some(ref mut rec) = state.recording {
+                    rec.write_samples(&chunk[..]);
+                },
+                TapEvent::Tuned(freq, _) => {
+                    state.tuned = Some(freq);
+
+                    if let Some(ref mut rec) = state.recording {
+                        rec.start_capture(freq);
+                    }
+                },
+                TapEvent::Configured(settings, correction) => {
+                    state.settings = Some((settings.clone(), correction));
+
+                    if let Some(ref mut rec) = state.recording {
+                        rec.configure(settings, correction);
+                    }
+                },
+            }
+        }
+
+        if let Some(mut rec) = self.state.lock().unwrap().recording.take() {
+            rec.finish();
+        }
+    }
+}
+
+/// A SigMF recording of the raw SDR stream, with a capture segment for each retune and
+/// each change to the SDR settings.
+struct IqRecording {
+    /// Stream of raw samples.
+    data: BufWriter<File>,
+    /// Path of the metadata file.
+    meta_path: PathBuf,
+    /// Metadata describing the samples written so far.
+    meta: sigmf::Meta,
+    /// Number of I/Q samples written so far.
+    samples: u64,
+    /// Current SDR settings and fine frequency correction (ppm), if known.
+    settings: Option<(SdrSettings, f32)>,
+}
+
+impl IqRecording {
+    /// Create the SigMF recording at the given base path, describing it with the given
+    /// global metadata and the given current SDR settings and correction (ppm).
+    fn create<P: AsRef<Path>>(base: P, mut global: sigmf::Global,
+                              settings: Option<(SdrSettings, f32)>)
+        -> std::io::Result<Self>
+    {
+        if let Some((ref s, _)) = settings {
+            global.sample_rate = s.sample_rate as f64;
+            global.ppm = Some(s.ppm);
+            global.gain = gain_db(s);
+        }
+
+        let (data_path, meta_path) = sigmf::paths(base);
+        let meta = sigmf::Meta::new(global);
+
+        try!(meta.save(&meta_path));
+
+        Ok(IqRecording {
+            data: BufWriter::new(try!(File::create(data_path))),
+            meta_path: meta_path,
+            meta: meta,
+            samples: 0,
+            settings: settings,
+        })
+    }
+
+    fn write_samples(&mut self, chunk: &[u8]) {
+        // The frequency of samples read before the first tuning is unknown.
+        if self.meta.captures.is_empty() {
+            return;
+        }
+
+        self.data.write_all(chunk).expect("unable to write I/Q recording");
+        self.samples += (chunk.len() / 2) as u64;
+    }
+
+    fn start_capture(&mut self, freq: u32) {
+        let (ppm, gain, correction) = match self.settings {
+            Some((ref s, c)) => (Some(s.ppm), gain_db(s), Some(c as f64)),
+            None => (None, None, None),
+        };
+
+        // The retune affects samples still buffered in the SDR, so the segment boundary
+        // is only accurate to within a few chunks.
+        self.meta.captures.push(sigmf::Capture {
+            sample_start: self.samples,
+            frequency: Some(freq as f64),
+            datetime: Some(UTC::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+            ppm: ppm,
+            gain: gain,
+            correction: correction,
+        });
+
+        self.data.flush().expect("unable to flush I/Q recording");
+        self.meta.save(&self.meta_path).expect("unable to write I/Q metadata");
+    }
+
+    fn configure(&mut self, settings: SdrSettings, correction: f32) {
+        let settings = Some((settings, correction));
+
+        if settings == self.settings {
+            return;
+        }
+
+        self.settings = settings;
+
+        // Samples from here on continue on the same frequency with the new settings.
+        let freq = self.meta.captures.last().and_then(|c| c.frequency);
+
+        if let Some(f) = freq {
+            self.start_capture(f as u32);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.data.flush().expect("unable to flush I/Q recording");
+    }
+}
+
+/// Get the manual tuner gain (dB) in the given settings, or `None` if the tuner AGC is
+/// enabled.
+fn gain_db(s: &SdrSettings) -> Option<f64> {
+    s.gain.map(|g| g as f64 / 10.0)
+}
+
+/// Time covered by one chunk of samples at the given sample rate (Hz).
+fn chunk_period(rate: u32) -> Duration {
+    let nanos = BUF_SAMPLES as u64 * 1_000_000_000 / rate as u64;
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use consts::SDR_SAMPLE_RATE;
+
+    #[test]
+    fn test_read_chunks() {
+        let bytes: Vec<u8> = (0..BUF_BYTES * 5 / 2).map(|i| i as u8).collect();
+        let mut src = IqFileSource::new(Cursor::new(bytes), SDR_SAMPLE_RATE);
+        let mut chunks = vec![];
+
+        src.read_chunks(|c| chunks.push(c.to_vec())).unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0][0], 0);
+        assert_eq!(chunks[1][0], (BUF_BYTES % 256) as u8);
+        assert_eq!(chunks[1].len(), BUF_BYTES);
+    }
+
+    #[test]
+    fn test_replay_retunes() {
+        let bytes = vec![7; BUF_BYTES * 2];
+
+        let captures = [
+            sigmf::Capture {
+                sample_start: 0,
+                frequency: Some(851e6),
+                datetime: None,
+                ppm: None,
+                gain: None,
+                correction: None,
+            },
+            sigmf::Capture {
+                sample_start: BUF_SAMPLES as u64 + 100,
+                frequency: Some(852e6),
+                datetime: None,
+                ppm: None,
+                gain: None,
+                correction: None,
+            },
+        ];
+
+        let (mut src, mut control) =
+            IqFileSource::with_captures(Cursor::new(bytes), SDR_SAMPLE_RATE,
+                                        &captures[..]);
+
+        control.set_center_freq(851_000_000).unwrap();
+
+        let mut chunks = vec![];
+        src.read_chunks(|c| chunks.push(c.to_vec())).unwrap();
+
+        assert!(chunks[0].iter().all(|&b| b == 7));
+        assert!(chunks[1][..200].iter().all(|&b| b == 7));
+        assert!(chunks[1][200..].iter().all(|&b| b == 128));
+    }
+
+    #[test]
+    fn test_replay_unknown_freq() {
+        let bytes = vec![7; BUF_BYTES * 2];
+
+        let captures = [
+            sigmf::Capture {
+                sample_start: 0,
+                frequency: None,
+                datetime: None,
+                ppm: None,
+                gain: None,
+                correction: None,
+            },
+            sigmf::Capture {
+                sample_start: BUF_SAMPLES as u64,
+                frequency: Some(852e6),
+                datetime: None,
+                ppm: None,
+                gain: None,
+                correction: None,
+            },
+        ];
+
+        let (mut src, mut control) =
+            IqFileSource::with_captures(Cursor::new(bytes), SDR_SAMPLE_RATE,
+                                        &captures[..]);
+
+        control.set_center_freq(851_000_000).unwrap();
+
+        let mut chunks = vec![];
+        src.read_chunks(|c| chunks.push(c.to_vec())).unwrap();
+
+        // Samples from an unknown frequency are passed through whatever the tuning.
+        assert!(chunks[0].iter().all(|&b| b == 7));
+        assert!(chunks[1].iter().all(|&b| b == 128));
+    }
+}
@@ -37,40 +37,66 @@ extern crate uhttp_uri;
 extern crate uhttp_version;
 
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Write};
-use std::sync::mpsc::channel;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::mpsc::{Sender, Receiver, channel};
 
-use clap::{Arg, App};
-use rtlsdr::TunerGains;
+use clap::{Arg, App, ArgMatches};
 
+mod afc;
 mod audio;
+mod baseband;
 mod consts;
+mod cqpsk;
 mod demod;
+mod devices;
+mod dsp;
+mod eye;
+mod gainopt;
 mod http;
 mod hub;
+mod hunt;
+mod iqfile;
+mod quality;
 mod recv;
+mod rtltcp;
+mod sched;
 mod sdr;
+mod sigmf;
+mod spectrum;
+mod wideband;
 
 use audio::{AudioOutput, AudioTask};
 use consts::SDR_SAMPLE_RATE;
-use demod::DemodTask;
+use demod::{DemodTask, Modulation};
 use hub::HubTask;
-use recv::{RecvTask, ReplayReceiver};
-use sdr::{ReadTask, ControlTask};
+use iqfile::{IqFileSource, IqRecorder};
+use recv::{RecvTask, ReplayReceiver, RecordKind};
+use rtltcp::{ServerTask, TunePolicy};
+use sdr::{ReadTask, ControlTask, ControlTaskEvent, SampleSource, SdrControl, SdrSettings};
+use sdr::{NoControl, Opener, valid_sample_rate, MIN_FAST_RATE, MAX_FAST_RATE};
+use wideband::ChannelizerTask;
 
 fn main() {
     let args = App::new("p25rx")
         .arg(Arg::with_name("ppm")
              .short("p")
-             .help("ppm frequency adjustment")
+             .help("ppm frequency adjustment (comma-separated list for each device)")
              .value_name("PPM"))
+        .arg(Arg::with_name("afc")
+             .long("afc")
+             .help("continuously correct tuning error measured on the control channel"))
         .arg(Arg::with_name("audio")
              .short("a")
              .help("file/fifo for audio samples (f32le/8kHz/mono)")
              .value_name("FILE"))
         .arg(Arg::with_name("gain")
              .short("g")
-             .help("tuner gain (use -g list to see all options)")
+             .help("tuner gain, auto for tuner AGC, or opt to choose by decode rate \
+                    (use -g list to see all options)")
              .value_name("GAIN"))
         .arg(Arg::with_name("replay")
              .short("r")
@@ -80,82 +106,386 @@ fn main() {
              .short("w")
              .help("write baseband samples to FILE")
              .value_name("FILE"))
+        .arg(Arg::with_name("write-iq")
+             .short("W")
+             .help("record raw I/Q samples to BASE.sigmf-data with SigMF metadata")
+             .value_name("BASE"))
+        .arg(Arg::with_name("record-dir")
+             .long("record-dir")
+             .help("directory for recordings started over HTTP (default: .)")
+             .value_name("DIR"))
+        .arg(Arg::with_name("iq")
+             .short("i")
+             .help("read I/Q samples (rtl_sdr cu8 format) from FILE instead of rtlsdr")
+             .value_name("FILE"))
+        .arg(Arg::with_name("rtltcp")
+             .short("t")
+             .help("read I/Q samples from rtl_tcp server at ADDR instead of rtlsdr")
+             .value_name("ADDR"))
+        .arg(Arg::with_name("serve")
+             .short("s")
+             .help("serve raw I/Q samples to rtl_tcp clients at ADDR")
+             .value_name("ADDR"))
+        .arg(Arg::with_name("serve-tuning")
+             .long("serve-tuning")
+             .help("rtl_tcp client tuning commands: deny or allow (default: deny)")
+             .value_name("POLICY"))
         .arg(Arg::with_name("freq")
              .short("f")
-             .help("frequency for initial control channel (Hz)")
+             .help("control channel frequency (Hz), or comma-separated list of \
+                    candidates to hunt through")
              .value_name("FREQ"))
         .arg(Arg::with_name("device")
              .short("d")
-             .help("rtlsdr device index (use -d list to show all)")
-             .value_name("INDEX"))
+             .help("rtlsdr device serial or index, or comma-separated control and voice \
+                    devices (use -d list to show all)")
+             .value_name("DEVICE"))
+        .arg(Arg::with_name("rate")
+             .long("rate")
+             .help("SDR sample rate: 240000 (default), or 960000 to 3120000 in steps of \
+                    240000")
+             .value_name("RATE"))
+        .arg(Arg::with_name("wideband")
+             .long("wideband")
+             .help("sample the SDR at RATE and extract every channel from the one stream \
+                    (RATE takes the same values as --rate)")
+             .value_name("RATE"))
+        .arg(Arg::with_name("center")
+             .long("center")
+             .help("fixed SDR center frequency in wideband mode (Hz)")
+             .value_name("FREQ"))
+        .arg(Arg::with_name("channels")
+             .long("channels")
+             .help("number of voice channels extracted in wideband mode (default: 2)")
+             .value_name("COUNT"))
+        .arg(Arg::with_name("offset")
+             .long("offset")
+             .help("tune the SDR HZ away from each channel to keep the DC spike off it \
+                    (e.g. 50000)")
+             .value_name("HZ"))
+        .arg(Arg::with_name("modulation")
+             .long("modulation")
+             .help("system modulation: c4fm (default) or cqpsk for simulcast (LSM) \
+                    systems")
+             .value_name("MOD"))
+        .arg(Arg::with_name("spectrum")
+             .long("spectrum")
+             .help("publish spectrum frames of the SDR band with SIZE bins (power of two \
+                    from 64 to 8192)")
+             .value_name("SIZE"))
+        .arg(Arg::with_name("spectrum-rate")
+             .long("spectrum-rate")
+             .help("spectrum frames per second (default: 4)")
+             .value_name("FPS"))
+        .arg(Arg::with_name("watchdog")
+             .long("watchdog")
+             .help("return to the control channel after MS without a decoded voice \
+                    channel message, or 0 to never (default: 3000)")
+             .value_name("MS"))
+        .arg(Arg::with_name("hang")
+             .long("hang")
+             .help("stay on a voice channel for MS after each transmission ends \
+                    (default: 0)")
+             .value_name("MS"))
         .arg(Arg::with_name("bind")
              .short("b")
              .help("HTTP socket bind address (default: 0.0.0.0:8025)")
              .value_name("BIND"))
         .get_matches();
 
-    let audio_out = || {
-        AudioOutput::new(BufWriter::new(
-            OpenOptions::new()
-                .write(true)
-                .open(args.value_of("audio").expect("-a option is required"))
-                .expect("unable to open audio output file")
-        ))
-    };
-
     if let Some(path) = args.value_of("replay") {
         let mut stream = File::open(path).expect("unable to open replay file");
-        let mut recv = ReplayReceiver::new(audio_out());
+        let mut recv = ReplayReceiver::new(audio_out(&args));
 
         recv.replay(&mut stream);
 
         return;
     }
 
-    let ppm: i32 = match args.value_of("ppm") {
-        Some(s) => s.parse().expect("invalid ppm"),
-        None => 0,
-    };
+    if let Some(path) = args.value_of("iq") {
+        let (data_path, meta_path) = sigmf::paths(path);
+
+        if !meta_path.exists() {
+            let stream = File::open(path).expect("unable to open I/Q file");
+            let source = IqFileSource::new(BufReader::new(stream), sample_rate(&args));
+
+            run(&args, vec![(NoControl, source, None)], None);
+
+            return;
+        }
+
+        let meta = sigmf::Meta::read(File::open(meta_path)
+            .expect("unable to open SigMF metadata"))
+            .expect("invalid SigMF metadata");
+
+        if meta.global.datatype != sigmf::DATATYPE_CU8 {
+            panic!("unsupported SigMF datatype");
+        }
+
+        let rate = sample_rate(&args);
+
+        if meta.global.sample_rate != rate as f64 {
+            panic!("recording sample rate is {} (use --rate or --wideband to match)",
+                   meta.global.sample_rate);
+        }
+
+        let stream = File::open(data_path).expect("unable to open SigMF data");
+        let (source, control) = IqFileSource::with_captures(BufReader::new(stream), rate,
+                                                            &meta.captures[..]);
+
+        // Default to the frequency the recording started on, which is normally the
+        // control channel.
+        let freq = meta.captures.iter().filter_map(|c| c.frequency).next()
+            .map(|f| f as u32);
+
+        run(&args, vec![(control, source, None)], freq);
+
+        return;
+    }
+
+    if let Some(addr) = args.value_of("rtltcp") {
+        let (mut control, reader) = rtltcp::connect(addr)
+            .expect("unable to connect to rtl_tcp server");
 
-    let samples_file = args.value_of("write")
-        .map(|path| File::create(path).expect("unable to open baseband file"));
+        let addr = addr.to_string();
+        let reconnect: Opener<_, _> = Box::new(move || rtltcp::connect(&addr[..]));
 
-    let dev: u32 = match args.value_of("device") {
+        if configure(&args, &mut control, 0) {
+            run(&args, vec![(control, reader, Some(reconnect))], None);
+        }
+
+        return;
+    }
+
+    let devs: Vec<String> = match args.value_of("device") {
         Some("list") => {
+            let serials = devices::serials();
+
             for (idx, name) in rtlsdr::devices().enumerate() {
-                println!("{}: {}", idx, name.to_str().unwrap());
+                let serial = match serials.get(idx) {
+                    Some(&Some(ref s)) => &s[..],
+                    _ => "unknown",
+                };
+
+                println!("{}: {} (serial {})", idx, name.to_str().unwrap(), serial);
             }
 
             return;
         },
-        Some(s) => s.parse().expect("invalid device index"),
-        None => 0,
+        Some(s) => s.split(',').map(|d| d.to_string()).collect(),
+        None => vec!["0".to_string()],
     };
 
-    let (mut control, reader) = rtlsdr::open(dev).expect("unable to open rtlsdr");
+    let mut tuners = vec![];
+
+    for (tuner, dev) in devs.into_iter().enumerate() {
+        let idx = match devices::lookup(&dev) {
+            Ok(idx) => idx,
+            Err(e) => panic!("{}: {}", dev, e),
+        };
+        let (mut control, reader) = rtlsdr::open(idx).expect("unable to open rtlsdr");
+
+        if !configure(&args, &mut control, tuner) {
+            return;
+        }
+
+        // The device is looked up again, since its index may have changed if it was
+        // unplugged.
+        let reopen: Opener<_, _> = Box::new(move || {
+            let idx = try!(devices::lookup(&dev).map_err(|e| {
+                std::io::Error::new(ErrorKind::NotFound, e.to_string())
+            }));
+
+            rtlsdr::open(idx).map_err(|_| {
+                std::io::Error::new(ErrorKind::Other, "unable to open rtlsdr")
+            })
+        });
+
+        tuners.push((control, reader, Some(reopen)));
+    }
+
+    run(&args, tuners, None);
+}
+
+/// Get the ppm correction given on the command line for the tuner with the given index.
+///
+/// Corrections can be listed in tuner order, and a single correction applies to every
+/// tuner.
+fn ppm(args: &ArgMatches, tuner: usize) -> Option<i32> {
+    args.value_of("ppm").map(|s| {
+        let list: Vec<&str> = s.split(',').collect();
+        list[std::cmp::min(tuner, list.len() - 1)].parse().expect("invalid ppm")
+    })
+}
+
+/// Apply the gain, ppm, and sample rate given on the command line to the SDR of the
+/// tuner with the given index.
+///
+/// Return `false` if the program should exit instead of receiving.
+fn configure<C: SdrControl>(args: &ArgMatches, control: &mut C, tuner: usize) -> bool {
+    let ppm = ppm(args, tuner).unwrap_or(0);
 
     match args.value_of("gain").expect("-g option is required") {
         "list" => {
-            let mut gains = TunerGains::default();
-
-            for g in control.tuner_gains(&mut gains) {
+            for g in control.gains() {
                 println!("{}", g);
             }
 
             println!("auto");
+            println!("opt");
 
-            return;
+            return false;
         },
-        "auto" => control.enable_agc().expect("unable to enable agc"),
+        // The optimizer sets the control tuner's gain once receiving starts.
+        "auto" | "opt" => control.enable_agc().expect("unable to enable agc"),
         s => control.set_tuner_gain(s.parse().expect("invalid gain"))
                 .expect("unable to set gain")
     }
 
     control.set_ppm(ppm).expect("unable to set ppm");
-    control.set_sample_rate(SDR_SAMPLE_RATE).expect("unable to set sample rate");
+    control.set_sample_rate(sample_rate(args)).expect("unable to set sample rate");
 
-    let freq: u32 = args.value_of("freq").expect("-f option is required")
-        .parse().expect("invalid frequency");
+    true
+}
+
+/// Get the settings given on the command line for the given SDR of the tuner with the
+/// given index.
+fn sdr_settings<C: SdrControl>(args: &ArgMatches, control: &mut C, tuner: usize)
+    -> SdrSettings
+{
+    SdrSettings {
+        gain: match args.value_of("gain") {
+            Some("auto") | Some("opt") | None => None,
+            Some(s) => Some(s.parse().expect("invalid gain")),
+        },
+        ppm: ppm(args, tuner).unwrap_or(0),
+        direct_sampling: 0,
+        bias_tee: false,
+        gains: control.gains(),
+        sample_rate: sample_rate(args),
+    }
+}
+
+/// Get the offset tuning (Hz) selected on the command line.
+fn offset(args: &ArgMatches) -> i32 {
+    let offset: i32 = match args.value_of("offset") {
+        Some(s) => s.parse().expect("invalid offset"),
+        None => return 0,
+    };
+
+    // The channel has to stay well inside the SDR bandwidth.
+    if offset.abs() > 100_000 {
+        panic!("offset must be at most 100000 Hz");
+    }
+
+    offset
+}
+
+/// Get the system modulation selected on the command line.
+fn modulation(args: &ArgMatches) -> Modulation {
+    match args.value_of("modulation") {
+        Some("c4fm") | None => Modulation::C4fm,
+        Some("cqpsk") => Modulation::Cqpsk,
+        Some(_) => panic!("modulation must be c4fm or cqpsk"),
+    }
+}
+
+/// Get the spectrum size and frame rate selected on the command line, if enabled.
+fn spectrum(args: &ArgMatches) -> Option<(usize, u32)> {
+    let size: usize = match args.value_of("spectrum") {
+        Some(s) => s.parse().expect("invalid spectrum size"),
+        None => return None,
+    };
+
+    if !spectrum::valid_size(size) {
+        panic!("spectrum size must be a power of two from {} to {}",
+               spectrum::MIN_SIZE, spectrum::MAX_SIZE);
+    }
+
+    let fps: u32 = args.value_of("spectrum-rate").unwrap_or("4").parse()
+        .expect("invalid spectrum rate");
+
+    if fps == 0 || fps > 30 {
+        panic!("spectrum rate must be from 1 to 30 frames per second");
+    }
+
+    Some((size, fps))
+}
+
+/// Get the voice channel watchdog and hang times (ms) selected on the command line.
+fn voice_timers(args: &ArgMatches) -> (u32, u32) {
+    let watchdog = args.value_of("watchdog").unwrap_or("3000").parse()
+        .expect("invalid watchdog time");
+    let hang = args.value_of("hang").unwrap_or("0").parse()
+        .expect("invalid hang time");
+
+    (watchdog, hang)
+}
+
+/// Get the SDR sample rate (Hz) selected on the command line.
+fn sample_rate(args: &ArgMatches) -> u32 {
+    let s = match (args.value_of("rate"), args.value_of("wideband")) {
+        (Some(_), Some(_)) => panic!("--rate and --wideband can't be combined"),
+        (Some(s), None) | (None, Some(s)) => s,
+        (None, None) => return SDR_SAMPLE_RATE,
+    };
+
+    let rate: u32 = s.parse().expect("invalid sample rate");
+
+    if !valid_sample_rate(rate) {
+        panic!("sample rate must be {0}, or {1} to {2} in steps of {0}", SDR_SAMPLE_RATE,
+               MIN_FAST_RATE, MAX_FAST_RATE);
+    }
+
+    rate
+}
+
+/// Build SigMF metadata describing I/Q recordings, leaving the SDR settings to be filled
+/// in as each recording starts.
+fn sigmf_global(args: &ArgMatches) -> sigmf::Global {
+    sigmf::Global {
+        datatype: sigmf::DATATYPE_CU8.to_string(),
+        sample_rate: sample_rate(args) as f64,
+        version: sigmf::VERSION.to_string(),
+        extensions: vec![sigmf::Extension::p25rx()],
+        recorder: Some("p25rx".to_string()),
+        ppm: None,
+        gain: None,
+    }
+}
+
+/// Open the audio output given on the command line.
+fn audio_out(args: &ArgMatches) -> AudioOutput<BufWriter<File>> {
+    AudioOutput::new(BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .open(args.value_of("audio").expect("-a option is required"))
+            .expect("unable to open audio output file")
+    ))
+}
+
+/// Run the receiver pipeline over the given tuners, each an SDR control and sample
+/// source along with a function for reopening the SDR if it can be recovered when lost,
+/// blocking the current thread.
+///
+/// The first tuner follows the control channel. With a single tuner, it also follows
+/// voice grants, and otherwise grants are handed out to the remaining tuners. In wideband
+/// mode, the first tuner stays at a fixed center frequency and the control and voice
+/// channels are all extracted from its stream.
+///
+/// The given frequency is used for the initial control channel if none was given on the
+/// command line.
+fn run<C, S>(args: &ArgMatches, tuners: Vec<(C, S, Option<Opener<C, S>>)>,
+             freq: Option<u32>)
+    where C: SdrControl + Send + 'static, S: SampleSource + Send + 'static
+{
+    let freqs: Vec<u32> = match args.value_of("freq") {
+        Some(s) => s.split(',').map(|f| f.parse().expect("invalid frequency")).collect(),
+        None => vec![freq.expect("-f option is required")],
+    };
+
+    // The first candidate is tried first.
+    let freq = freqs[0];
 
     let addr = args.value_of("bind").unwrap_or("0.0.0.0:8025").parse()
         .expect("unable to bind tcp socket");
@@ -166,14 +496,221 @@ fn main() {
     let (tx_audio, rx_audio) = channel();
     let (tx_hub, rx_hub) = mio::channel::channel();
 
-    let mut hub = HubTask::new(rx_hub, tx_recv.clone(), &addr)
+    let offset = offset(args);
+    let modulation = modulation(args);
+    let mut tuners = tuners.into_iter();
+    let (mut control, source, reopen) = tuners.next().expect("no tuners given");
+
+    let mut voice = vec![];
+    let mut voice_recv = vec![];
+    let mut tx_voice = vec![];
+
+    for (t, (mut control, source, reopen)) in tuners.enumerate() {
+        let (tx_ctl, rx_ctl) = channel();
+        let (tx_read, rx_read) = channel();
+        let (tx, rx) = channel();
+        let generation = Arc::new(AtomicUsize::new(0));
+
+        let settings = sdr_settings(args, &mut control, t + 1);
+        let mut control = ControlTask::new(control, rx_ctl, generation.clone());
+        let mut demod = DemodTask::new(sample_rate(args), rx_read, None, tx.clone());
+
+        control.set_settings(settings);
+        control.set_offset(offset);
+        demod.set_offset(offset);
+        demod.set_modulation(modulation);
+
+        let reopen = reopen.map(|open| (tx_ctl.clone(), control.set_reopen(open)));
+
+        voice.push(VoiceChain {
+            control: control,
+            read: ReadTask::new(tx_read, generation.clone()),
+            source: source,
+            reopen: reopen,
+            demod: demod,
+        });
+
+        let mut recv = RecvTask::new_voice(t, tx_recv.clone(), rx, tx_hub.clone(),
+            tx_ctl, tx_audio.clone());
+
+        recv.track_tuning(generation, true);
+
+        voice_recv.push(recv);
+        tx_voice.push(tx);
+    }
+
+    let channelizer = if args.is_present("wideband") {
+        if !voice.is_empty() {
+            panic!("wideband mode uses a single device");
+        }
+
+        // Channels are already extracted away from the center.
+        if offset != 0 {
+            panic!("offset tuning doesn't apply in wideband mode");
+        }
+
+        let center: u32 = args.value_of("center").expect("--center option is required")
+            .parse().expect("invalid center frequency");
+        let count: usize = args.value_of("channels").unwrap_or("2").parse()
+            .expect("invalid channel count");
+
+        // Tune through the control task so I/Q taps learn the center frequency.
+        tx_ctl.send(ControlTaskEvent::SetFreq(center)).expect("unable to set frequency");
+
+        Some((center, count))
+    } else {
+        None
+    };
+
+    let generation = Arc::new(AtomicUsize::new(0));
+    let mut read = ReadTask::new(tx_read, generation.clone());
+
+    let server = args.value_of("serve").map(|addr| {
+        let policy = match args.value_of("serve-tuning").unwrap_or("deny") {
+            "deny" => TunePolicy::Deny,
+            "allow" if channelizer.is_none() => TunePolicy::Allow,
+            "allow" => panic!("rtl_tcp clients can't tune in wideband mode"),
+            _ => panic!("invalid rtl_tcp tuning policy"),
+        };
+
+        let listener = TcpListener::bind(addr).expect("unable to bind rtl_tcp socket");
+        let (tx, rx) = channel();
+
+        read.add_tap(tx);
+
+        ServerTask::new(listener, &control.gains()[..], policy, rx, tx_ctl.clone())
+    });
+
+    let settings = sdr_settings(args, &mut control, 0);
+    let gains = settings.gains.clone();
+    let mut control = ControlTask::new(control, rx_ctl, generation.clone());
+
+    control.set_settings(settings);
+    control.set_hub(tx_hub.clone());
+    control.set_offset(offset);
+
+    let reopen = reopen.map(|open| (tx_ctl.clone(), control.set_reopen(open)));
+
+    let (tx_iq, rx_iq) = channel();
+    let (iq, mut recorder) = IqRecorder::new(sigmf_global(args), rx_iq);
+
+    // Retunes are always tracked so a recording can start on the right frequency.
+    read.add_gated_tap(tx_iq.clone(), iq.active());
+    control.add_tap(tx_iq);
+
+    let mut hub = HubTask::new(rx_hub, tx_recv.clone(), tx_ctl.clone(), &addr)
         .expect("unable to start hub");
-    let mut control = ControlTask::new(control, rx_ctl);
-    let mut read = ReadTask::new(tx_read);
-    let mut demod = DemodTask::new(rx_read, tx_hub.clone(), tx_recv.clone());
-    let mut recv = RecvTask::new(freq, rx_recv, tx_hub.clone(),
-        tx_ctl.clone(), tx_audio.clone());
-    let mut audio = AudioTask::new(audio_out(), rx_audio);
+
+    let (demod, channelizer, mut recv) = match channelizer {
+        Some((center, count)) => {
+            let rate = sample_rate(args);
+            let (min, max) = wideband::channel_range(center, rate);
+
+            if freqs.iter().any(|&f| f < min || f > max) {
+                panic!("control channel is outside the wideband range");
+            }
+
+            let mut chz = ChannelizerTask::new(center, rate, rx_read);
+            chz.set_modulation(modulation);
+
+            if let Some((size, fps)) = spectrum(args) {
+                chz.enable_spectrum(size, fps, tx_hub.clone());
+            }
+
+            let (tx_tune, rx_tune) = channel();
+            let ctl_generation = Arc::new(AtomicUsize::new(0));
+
+            chz.add_channel(rx_tune, tx_recv.clone(), Some(tx_hub.clone()),
+                ctl_generation.clone());
+
+            // Channels retune instantly since the SDR itself stays put.
+            for t in 0..count {
+                let (tx_tune, rx_tune) = channel();
+                let (tx, rx) = channel();
+                let generation = Arc::new(AtomicUsize::new(0));
+
+                chz.add_channel(rx_tune, tx.clone(), None, generation.clone());
+
+                let mut recv = RecvTask::new_voice(t, tx_recv.clone(), rx,
+                    tx_hub.clone(), tx_tune, tx_audio.clone());
+
+                recv.track_tuning(generation, false);
+
+                voice_recv.push(recv);
+                tx_voice.push(tx);
+            }
+
+            let mut recv = RecvTask::new(freq, rx_recv, tx_hub.clone(), tx_tune,
+                tx_audio);
+
+            recv.set_tuning_range(min, max);
+            recv.track_tuning(ctl_generation, false);
+
+            (None, Some(chz), recv)
+        },
+        None => {
+            let mut demod = DemodTask::new(sample_rate(args), rx_read,
+                Some(tx_hub.clone()), tx_recv.clone());
+
+            demod.set_offset(offset);
+            demod.set_modulation(modulation);
+
+            if let Some((size, fps)) = spectrum(args) {
+                let (tx, rx) = channel();
+
+                control.add_tap(tx);
+                demod.enable_spectrum(size, fps, rx);
+            }
+
+            // The tap stays off until a client asks for it.
+            let eye = Arc::new(AtomicBool::new(false));
+            demod.enable_eye(eye.clone());
+            hub.enable_eye(eye);
+
+            let mut recv = RecvTask::new(freq, rx_recv, tx_hub.clone(), tx_ctl.clone(),
+                tx_audio);
+
+            recv.track_tuning(generation.clone(), true);
+
+            (Some(demod), None, recv)
+        },
+    };
+
+    if !tx_voice.is_empty() {
+        recv.set_voice_tuners(tx_voice);
+    }
+
+    recv.add_control_freqs(&freqs[1..]);
+
+    if args.is_present("afc") {
+        recv.enable_afc();
+    }
+
+    let (watchdog, hang) = voice_timers(&args);
+    recv.set_voice_timers(watchdog, hang);
+
+    if args.value_of("gain") == Some("opt") {
+        if gains.is_empty() {
+            panic!("SDR has no gains to optimize");
+        }
+
+        recv.enable_gain_opt(gains, tx_ctl.clone());
+    }
+
+    recv.set_iq_recorder(iq);
+    recv.set_record_dir(PathBuf::from(args.value_of("record-dir").unwrap_or(".")));
+
+    if let Some(path) = args.value_of("write") {
+        recv.start_recording(RecordKind::Baseband, Some(PathBuf::from(path)))
+            .expect("unable to open baseband file");
+    }
+
+    if let Some(base) = args.value_of("write-iq") {
+        recv.start_recording(RecordKind::Iq, Some(PathBuf::from(base)))
+            .expect("unable to create I/Q recording");
+    }
+
+    let mut audio = AudioTask::new(audio_out(args), rx_audio);
 
     crossbeam::scope(|scope| {
         scope.spawn(move || {
@@ -188,34 +725,92 @@ fn main() {
 
         scope.spawn(move || {
             prctl::set_name("reader").unwrap();
-            read.run(reader);
+
+            match reopen {
+                Some((lost, sources)) => read.run_reopening(source, lost, sources),
+                None => read.run(source),
+            }
         });
 
+        if let Some(mut server) = server {
+            scope.spawn(move || {
+                prctl::set_name("rtltcp").unwrap();
+                server.run();
+            });
+        }
+
         scope.spawn(move || {
-            prctl::set_name("demod").unwrap();
-            demod.run();
+            prctl::set_name("recorder").unwrap();
+            recorder.run();
         });
 
+        if let Some(mut demod) = demod {
+            scope.spawn(move || {
+                prctl::set_name("demod").unwrap();
+                demod.run();
+            });
+        }
+
+        if let Some(mut chz) = channelizer {
+            scope.spawn(move || {
+                prctl::set_name("channelizer").unwrap();
+                chz.run();
+            });
+        }
+
+        for chain in voice {
+            let VoiceChain { mut control, mut read, source, reopen, mut demod } = chain;
+
+            scope.spawn(move || {
+                prctl::set_name("voice-ctl").unwrap();
+                control.run();
+            });
+
+            scope.spawn(move || {
+                prctl::set_name("voice-reader").unwrap();
+
+                match reopen {
+                    Some((lost, sources)) => read.run_reopening(source, lost, sources),
+                    None => read.run(source),
+                }
+            });
+
+            scope.spawn(move || {
+                prctl::set_name("voice-demod").unwrap();
+                demod.run();
+            });
+        }
+
+        for mut recv in voice_recv {
+            recv.set_voice_timers(watchdog, hang);
+
+            scope.spawn(move || {
+                prctl::set_name("voice-recv").unwrap();
+                recv.run();
+            });
+        }
+
         scope.spawn(move || {
             prctl::set_name("receiver").unwrap();
-
-            if let Some(mut f) = samples_file {
-                recv.run(|samples| {
-                    f.write_all(unsafe {
-                        std::slice::from_raw_parts(
-                            samples.as_ptr() as *const u8,
-                            samples.len() * std::mem::size_of::<f32>()
-                        )
-                    }).expect("unable to write baseband");
-                })
-            } else {
-                recv.run(|_| {})
-            }
+            recv.run();
         });
 
         scope.spawn(move || {
             prctl::set_name("audio").unwrap();
             audio.run();
+
+            // The audio task only finishes once the sample source has been exhausted and
+            // the rest of the pipeline has drained, so the other tasks can be dropped.
+            std::process::exit(0);
         });
     });
 }
+
+/// Tasks driving a voice tuner's SDR.
+struct VoiceChain<C: SdrControl, S: SampleSource> {
+    control: ControlTask<C>,
+    read: ReadTask,
+    source: S,
+    reopen: Option<(Sender<ControlTaskEvent>, Receiver<S>)>,
+    demod: DemodTask,
+}
@@ -0,0 +1,323 @@
+//! Signal quality measurement.
+//!
+//! Channel power alone doesn't say whether a signal can be decoded, so each receiver also
+//! estimates the SNR of the filtered channel, how tightly demodulated symbols cluster
+//! around the ideal C4FM deviations, and how many errors the decoder had to correct, and
+//! summarizes them once a second.
+
+use std;
+
+use num::complex::Complex32;
+
+use consts::{BASEBAND_SAMPLE_RATE, SYMBOL_RATE};
+
+/// Number of baseband samples summarized in each report.
+const WINDOW: usize = BASEBAND_SAMPLE_RATE as usize;
+
+/// Number of baseband samples per symbol.
+pub const SAMPLES_PER_SYMBOL: usize = (BASEBAND_SAMPLE_RATE / SYMBOL_RATE) as usize;
+
+/// Ideal C4FM symbol deviations (Hz).
+pub const LEVELS: [f32; 4] = [-1800.0, -600.0, 600.0, 1800.0];
+
+/// Mean magnitude of the ideal deviations (Hz), assuming each symbol is equally likely.
+pub const MEAN_LEVEL: f32 = 1200.0;
+
+/// Lowest reported SNR (dB), given for a channel with no measurable signal.
+pub const MIN_SNR: f32 = -20.0;
+
+/// Highest reported SNR (dB), given for a channel with no measurable noise.
+pub const MAX_SNR: f32 = 60.0;
+
+/// Estimate the SNR (dB) of the given constant-envelope samples, such as a filtered FM
+/// channel, from their second and fourth moments.
+///
+/// The estimate is limited to `MIN_SNR` through `MAX_SNR`, and is NaN only if there are
+/// no samples.
+pub fn snr_db(samples: &[Complex32]) -> f32 {
+    if samples.is_empty() {
+        return std::f32::NAN;
+    }
+
+    let (m2, m4) = samples.iter().fold((0.0, 0.0), |(m2, m4), s| {
+        let p = s.norm_sqr();
+        (m2 + p, m4 + p * p)
+    });
+
+    let m2 = m2 / samples.len() as f32;
+    let m4 = m4 / samples.len() as f32;
+
+    // With a constant-envelope signal in complex Gaussian noise, 2*M2^2 - M4 leaves only
+    // the squared signal power.
+    let signal = (2.0 * m2 * m2 - m4).max(0.0).sqrt();
+    let noise = m2 - signal;
+
+    if noise <= 0.0 {
+        return if signal > 0.0 { MAX_SNR } else { MIN_SNR };
+    }
+
+    (10.0 * (signal / noise).log10()).max(MIN_SNR).min(MAX_SNR)
+}
+
+/// Measure the RMS distance (Hz) of the given C4FM baseband from the nearest ideal symbol
+/// deviation, sampled at the symbol phase where symbols cluster most tightly.
+///
+/// The baseband is scaled and offset to best fit the ideal deviations, so the result
+/// doesn't depend on demodulator gain or tuning error.
+pub fn symbol_spread(baseband: &[f32]) -> f32 {
+    spread_at(baseband, symbol_phase(baseband))
+}
+
+/// Find the offset of the first symbol center in the given C4FM baseband, where symbols
+/// cluster most tightly around the ideal deviations.
+pub fn symbol_phase(baseband: &[f32]) -> usize {
+    (0..SAMPLES_PER_SYMBOL).map(|phase| (phase, spread_at(baseband, phase)))
+        .fold((0, std::f32::INFINITY), |min, (phase, x)| {
+            if x < min.1 { (phase, x) } else { min }
+        }).0
+}
+
+/// Measure the symbol spread (Hz) of the given baseband sampled once a symbol, starting
+/// at the given sample.
+fn spread_at(baseband: &[f32], phase: usize) -> f32 {
+    let symbols = baseband.iter().enumerate()
+        .filter(|&(i, _)| i % SAMPLES_PER_SYMBOL == phase)
+        .map(|(_, &x)| x)
+        .collect::<Vec<f32>>();
+
+    if symbols.is_empty() {
+        return std::f32::INFINITY;
+    }
+
+    let count = symbols.len() as f32;
+
+    let mean = symbols.iter().fold(0.0, |s, &x| s + x) / count;
+    let mag = symbols.iter().fold(0.0, |s, &x| s + (x - mean).abs()) / count;
+
+    if mag == 0.0 {
+        return std::f32::INFINITY;
+    }
+
+    // Make a rough guess at the scale to decide each symbol, then refine the scale and
+    // offset with a least squares fit to the decided levels.
+    let rough = MEAN_LEVEL / mag;
+
+    let decided = symbols.iter().map(|&x| nearest((x - mean) * rough))
+                         .collect::<Vec<f32>>();
+    let mean_level = decided.iter().fold(0.0, |s, &l| s + l) / count;
+
+    let (cov, var) = symbols.iter().zip(decided.iter())
+        .fold((0.0, 0.0), |(cov, var), (&x, &l)| {
+            (cov + (x - mean) * (l - mean_level), var + (x - mean) * (x - mean))
+        });
+
+    let scale = cov / var;
+
+    let err = symbols.iter().fold(0.0, |s, &x| {
+        let dev = (x - mean) * scale + mean_level;
+        let e = dev - nearest(dev);
+
+        s + e * e
+    });
+
+    (err / count).sqrt()
+}
+
+/// Find the ideal deviation (Hz) nearest the given deviation.
+fn nearest(dev: f32) -> f32 {
+    LEVELS.iter().fold(LEVELS[0], |n, &l| {
+        if (dev - l).abs() < (dev - n).abs() { l } else { n }
+    })
+}
+
+/// Quality of the signal received over one report window.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Quality {
+    /// Receiver the measurements are from: 0 for the control channel receiver, or one
+    /// more than the index of a voice tuner.
+    pub tuner: usize,
+    /// Mean estimated SNR (dB) of the filtered channel.
+    pub snr: f32,
+    /// Mean RMS distance (Hz) of demodulated symbols from the ideal deviations.
+    pub spread: f32,
+    /// Number of voice frames decoded.
+    #[serde(rename = "voiceFrames")]
+    pub voice_frames: u32,
+    /// Number of bit errors corrected by FEC in decoded voice frames.
+    #[serde(rename = "voiceErrors")]
+    pub voice_errors: u32,
+    /// Number of TSBKs that passed their CRC.
+    #[serde(rename = "tsbkValid")]
+    pub tsbk_valid: u32,
+    /// Number of TSBKs that failed their CRC.
+    #[serde(rename = "tsbkFailed")]
+    pub tsbk_failed: u32,
+}
+
+/// Accumulates quality measurements into periodic reports.
+pub struct QualityMeter {
+    /// Measurements in the current window.
+    cur: Quality,
+    /// Number of samples in the current window.
+    count: usize,
+    /// Number of samples in the current window that contributed to the mean spread.
+    measured: usize,
+}
+
+impl QualityMeter {
+    /// Create a new `QualityMeter` for the receiver with the given tuner number.
+    pub fn new(tuner: usize) -> Self {
+        QualityMeter {
+            cur: Quality {
+                tuner: tuner,
+                ..Quality::default()
+            },
+            count: 0,
+            measured: 0,
+        }
+    }
+
+    /// Count a decoded voice frame with the given number of corrected bit errors.
+    pub fn voice_frame(&mut self, errors: usize) {
+        self.cur.voice_frames += 1;
+        self.cur.voice_errors += errors as u32;
+    }
+
+    /// Count a received TSBK that passed its CRC if the flag is set, or failed it
+    /// otherwise.
+    pub fn tsbk(&mut self, valid: bool) {
+        if valid {
+            self.cur.tsbk_valid += 1;
+        } else {
+            self.cur.tsbk_failed += 1;
+        }
+    }
+
+    /// Add a chunk with the given SNR (dB), symbol spread (Hz), and number of samples.
+    ///
+    /// Return a report when the chunk completes a window.
+    pub fn feed(&mut self, snr: f32, spread: f32, samples: usize) -> Option<Quality> {
+        // Only an empty chunk has no SNR.
+        if !snr.is_nan() {
+            self.cur.snr += snr * samples as f32;
+        }
+
+        // Chunks too short to find the symbol phase don't contribute to the spread.
+        if spread.is_finite() {
+            self.cur.spread += spread * samples as f32;
+            self.measured += samples;
+        }
+
+        self.count += samples;
+
+        if self.count < WINDOW {
+            return None;
+        }
+
+        let mut q = self.cur;
+
+        q.snr /= self.count as f32;
+
+        if self.measured > 0 {
+            q.spread /= self.measured as f32;
+        }
+
+        self.cur = Quality {
+            tuner: q.tuner,
+            ..Quality::default()
+        };
+        self.count = 0;
+        self.measured = 0;
+
+        Some(q)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use num::complex::Complex32;
+
+    /// Generate deterministic pseudo-random values uniform in [-1, 1), for tests
+    /// throughout the crate.
+    pub fn noise(count: usize) -> Vec<f32> {
+        let mut seed = 1u32;
+
+        (0..count).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 8) as f32 / (1 << 23) as f32 - 1.0
+        }).collect()
+    }
+
+    #[test]
+    fn test_snr() {
+        let n = noise(20000);
+
+        // Uniform noise in [-1, 1) has variance 1/3 on each axis.
+        let samples = (0..10000).map(|i| {
+            Complex32::from_polar(&2.0, &(i as f32 * 0.1)) +
+                Complex32::new(n[2 * i], n[2 * i + 1]) * 0.3
+        }).collect::<Vec<_>>();
+
+        let expected = 10.0 * (4.0 / (2.0 * 0.09 / 3.0f32)).log10();
+        assert!((snr_db(&samples[..]) - expected).abs() < 1.0);
+
+        let clean = (0..100).map(|i| Complex32::from_polar(&1.0, &(i as f32)))
+                            .collect::<Vec<_>>();
+        assert_eq!(snr_db(&clean[..]), MAX_SNR);
+
+        assert_eq!(snr_db(&[Complex32::new(0.0, 0.0); 100][..]), MIN_SNR);
+        assert!(snr_db(&[]).is_nan());
+    }
+
+    #[test]
+    fn test_symbol_spread() {
+        let n = noise(4801);
+        let symbols = n.iter().map(|&x| LEVELS[((x + 1.0) * 2.0) as usize] / 5000.0)
+                        .collect::<Vec<_>>();
+
+        // Symbols held for their whole period, offset from the chunk start.
+        let baseband = (0..48000).map(|i| symbols[(i + 3) / SAMPLES_PER_SYMBOL] + 0.01)
+                                 .collect::<Vec<_>>();
+        assert!(symbol_spread(&baseband[..48000 - 10]) < 5.0);
+
+        // Uniform noise with an RMS of about 144Hz spreads the symbols out.
+        let noisy = baseband.iter().zip(noise(48000).iter().rev())
+                            .map(|(&x, &n)| x + n * 0.05)
+                            .collect::<Vec<_>>();
+        let spread = symbol_spread(&noisy[..]);
+        assert!(spread > 120.0 && spread < 170.0);
+
+        assert!(symbol_spread(&[]).is_infinite());
+    }
+
+    #[test]
+    fn test_meter() {
+        let mut m = QualityMeter::new(2);
+
+        m.tsbk(true);
+        m.tsbk(false);
+        m.tsbk(true);
+        m.voice_frame(3);
+        m.voice_frame(0);
+
+        assert_eq!(m.feed(10.0, 100.0, WINDOW / 2), None);
+        assert_eq!(m.feed(20.0, std::f32::INFINITY, WINDOW / 4), None);
+        assert_eq!(m.feed(std::f32::NAN, std::f32::INFINITY, 0), None);
+
+        let q = m.feed(MAX_SNR, 200.0, WINDOW / 4).unwrap();
+        assert_eq!(q.tuner, 2);
+        // A chunk too short for the spread still counts toward the SNR.
+        assert!((q.snr - 25.0).abs() < 1e-3);
+        assert!((q.spread - 400.0 / 3.0).abs() < 1e-3);
+        assert_eq!(q.voice_frames, 2);
+        assert_eq!(q.voice_errors, 3);
+        assert_eq!(q.tsbk_valid, 2);
+        assert_eq!(q.tsbk_failed, 1);
+
+        let q = m.feed(0.0, 0.0, WINDOW).unwrap();
+        assert_eq!(q.tuner, 2);
+        assert_eq!(q.voice_frames, 0);
+        assert_eq!(q.tsbk_valid, 0);
+    }
+}
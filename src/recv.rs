@@ -1,9 +1,15 @@
 use std::collections::HashSet;
+use std::fs::File;
 use std::hash::BuildHasherDefault;
-use std::io::{Read, Write};
+use std::io::{Read, Write, BufWriter, ErrorKind};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Sender, Receiver};
+use std::time::Instant;
 use std;
 
+use chrono::UTC;
 use fnv::FnvHasher;
 use mio;
 use p25::message::nid::DataUnit;
@@ -13,13 +19,71 @@ use p25::trunking::tsbk::{TsbkOpcode};
 use p25::voice::crypto::CryptoAlgorithm;
 use pool::Checkout;
 
+use afc::Afc;
 use audio::{AudioEvent, AudioOutput};
+use baseband::{BasebandReader, BasebandWriter, Record};
+use consts::{BASEBAND_SAMPLE_RATE, FM_DEVIATION};
+use demod::ChunkStats;
+use gainopt::GainOptimizer;
+use hunt::ControlHunter;
+use iqfile::IqRecorder;
+use quality::QualityMeter;
+use sched::{TunerPool, Grant, Assign};
 use sdr::ControlTaskEvent;
-use hub::{HubEvent, StateEvent};
+use hub::{HubEvent, StateEvent, RecordingState};
+
+/// Number of baseband samples discarded after a retune, covering the tuner PLL settling
+/// and samples the SDR buffered before the retune.
+const RETUNE_SETTLE: usize = BASEBAND_SAMPLE_RATE as usize / 20;
+
+/// Number of baseband samples without carrier after which a voice call is considered
+/// over, covering brief fades.
+const CARRIER_HANG: usize = BASEBAND_SAMPLE_RATE as usize;
+
+/// Convert the given time (ms) to a number of baseband samples.
+fn ms_samples(ms: u32) -> usize {
+    ms as usize * BASEBAND_SAMPLE_RATE as usize / 1000
+}
 
 pub enum RecvEvent {
-    Baseband(Checkout<Vec<f32>>),
+    Baseband(Checkout<Vec<f32>>, ChunkStats),
     SetControlFreq(u32),
+    /// Start a recording of the given kind, reporting whether it started over the given
+    /// channel.
+    StartRecording(RecordKind, mio::channel::Sender<std::io::Result<()>>),
+    StopRecording(RecordKind),
+    /// Follow the voice channel at the given frequency (Hz) granted to the given
+    /// talkgroup.
+    VoiceGrant(u32, TalkGroup),
+    /// The voice tuner with the given index has finished following its grant, and the
+    /// call was encrypted if the flag is set.
+    VoiceReleased(usize, bool),
+    /// Stop optimizing the tuner gain, leaving it to be set manually.
+    StopGainOpt,
+    EndOfStream,
+}
+
+/// How a receiver uses its SDR.
+pub enum RecvRole {
+    /// Follow both the control channel and voice grants with a single tuner.
+    Single,
+    /// Stay on the control channel and hand voice grants to the voice tuners with the
+    /// contained channels.
+    Control(Vec<Sender<RecvEvent>>, TunerPool),
+    /// Follow voice grants handed out by the control tuner, with the contained index and
+    /// channel to the control receiver.
+    Voice(usize, Sender<RecvEvent>),
+}
+
+/// Kinds of recordings made by the receiver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordKind {
+    /// Demodulated baseband samples.
+    #[serde(rename = "baseband")]
+    Baseband,
+    /// Raw I/Q samples from the SDR.
+    #[serde(rename = "iq")]
+    Iq,
 }
 
 pub struct RecvTask {
@@ -33,6 +97,48 @@ pub struct RecvTask {
     hub: mio::channel::Sender<HubEvent>,
     sdr: Sender<ControlTaskEvent>,
     audio: Sender<AudioEvent>,
+    record: Option<BasebandWriter<BufWriter<File>>>,
+    iq: Option<IqRecorder>,
+    record_dir: PathBuf,
+    recording: RecordingState,
+    role: RecvRole,
+    /// Lowest and highest channel frequencies (Hz) that can be followed.
+    range: (u32, u32),
+    /// Frequency correction loop, if enabled.
+    afc: Option<Afc>,
+    /// Gain optimizer, if enabled, with the channel to the SDR whose gain it sets.
+    gainopt: Option<(GainOptimizer, Sender<ControlTaskEvent>)>,
+    /// Tuning generation of the SDR, if stale chunks are discarded.
+    generation: Option<Arc<AtomicUsize>>,
+    /// Oldest tuning generation whose chunks are from the current frequency.
+    expect: usize,
+    /// Tuning generation of the most recent chunk decoded.
+    seen: usize,
+    /// Whether samples are discarded at the start of each generation.
+    settle: bool,
+    /// Number of samples remaining to be discarded.
+    discard: usize,
+    /// Time of the most recent retune, until its first chunk arrives.
+    retuned: Option<Instant>,
+    /// Signal quality reports.
+    quality: QualityMeter,
+    /// Whether the previous chunk had a carrier.
+    carrier: bool,
+    /// Number of consecutive samples received without carrier.
+    quiet: usize,
+    /// Number of samples without a decoded message after which a voice call is
+    /// abandoned, or 0 to wait for the call to end on its own.
+    watchdog: usize,
+    /// Number of samples to stay on a voice channel after its transmission ends.
+    hang: usize,
+    /// Number of samples received on the current voice channel since a message was
+    /// decoded.
+    idle: usize,
+    /// Number of samples remaining before leaving the voice channel, if its transmission
+    /// has ended.
+    hanging: Option<usize>,
+    /// Control channel candidates, if this receiver follows the control channel.
+    hunt: Option<ControlHunter>,
 }
 
 impl RecvTask {
@@ -42,6 +148,36 @@ impl RecvTask {
                sdr: Sender<ControlTaskEvent>,
                audio: Sender<AudioEvent>)
         -> Self
+    {
+        RecvTask {
+            hunt: Some(ControlHunter::new(freq)),
+            ..RecvTask::with_role(RecvRole::Single, 0, events, hub, sdr, audio)
+        }.init(freq)
+    }
+
+    /// Create a new `RecvTask` for the voice tuner with the given index, which receives
+    /// grants from and reports back to the control receiver over the given channel.
+    pub fn new_voice(tuner: usize,
+                     control: Sender<RecvEvent>,
+                     events: Receiver<RecvEvent>,
+                     hub: mio::channel::Sender<HubEvent>,
+                     sdr: Sender<ControlTaskEvent>,
+                     audio: Sender<AudioEvent>)
+        -> Self
+    {
+        RecvTask::with_role(RecvRole::Voice(tuner, control), tuner + 1, events, hub, sdr,
+                            audio)
+    }
+
+    /// Create a new `RecvTask` in the given role, reporting signal quality under the
+    /// given tuner number, with no control channel set.
+    fn with_role(role: RecvRole,
+                 tuner: usize,
+                 events: Receiver<RecvEvent>,
+                 hub: mio::channel::Sender<HubEvent>,
+                 sdr: Sender<ControlTaskEvent>,
+                 audio: Sender<AudioEvent>)
+        -> Self
     {
         RecvTask {
             ctlfreq: std::u32::MAX,
@@ -54,7 +190,29 @@ impl RecvTask {
             hub: hub,
             sdr: sdr,
             audio: audio,
-        }.init(freq)
+            record: None,
+            iq: None,
+            record_dir: PathBuf::from("."),
+            recording: RecordingState::default(),
+            role: role,
+            range: (0, std::u32::MAX),
+            afc: None,
+            gainopt: None,
+            generation: None,
+            expect: 0,
+            seen: 0,
+            settle: false,
+            discard: 0,
+            retuned: None,
+            quality: QualityMeter::new(tuner),
+            carrier: true,
+            quiet: 0,
+            watchdog: 0,
+            hang: 0,
+            idle: 0,
+            hanging: None,
+            hunt: None,
+        }
     }
 
     fn init(mut self, freq: u32) -> Self {
@@ -63,14 +221,58 @@ impl RecvTask {
     }
 
     fn set_control_freq(&mut self, freq: u32) {
+        // Decode rates on another control channel aren't comparable.
+        if freq != self.ctlfreq {
+            if let Some((ref mut opt, _)) = self.gainopt {
+                opt.reset();
+            }
+        }
+
         self.ctlfreq = freq;
         self.hub.send(HubEvent::State(StateEvent::UpdateCtlFreq(freq)))
             .expect("unable to send control frequency");
         self.switch_control();
     }
 
+    /// Add the given frequencies (Hz) to the control channel candidates tried when the
+    /// current control channel stops decoding.
+    pub fn add_control_freqs(&mut self, freqs: &[u32]) {
+        if let Some(ref mut hunt) = self.hunt {
+            for &freq in freqs {
+                hunt.add(freq);
+            }
+        }
+    }
+
+    /// Switch to the given control channel frequency (Hz) chosen by the user.
+    fn select_control_freq(&mut self, freq: u32) {
+        if let Some(ref mut hunt) = self.hunt {
+            hunt.select(freq);
+        }
+
+        self.set_control_freq(freq);
+    }
+
+    /// Advance the control channel timeout by the given number of samples, moving to the
+    /// next candidate if no valid TSBK has been received for too long.
+    fn update_hunt(&mut self, samples: usize) {
+        // Time spent following voice doesn't count against the control channel.
+        if self.curfreq != self.ctlfreq {
+            return;
+        }
+
+        let next = match self.hunt {
+            Some(ref mut hunt) => hunt.feed(samples),
+            None => return,
+        };
+
+        if let Some(freq) = next {
+            self.set_control_freq(freq);
+        }
+    }
+
     fn switch_control(&mut self) {
-        self.audio.send(AudioEvent::EndTransmission)
+        self.audio.send(AudioEvent::EndTransmission(self.source()))
             .expect("unable to send end of transmission");
 
         // FIXME: non-lexical borrowing
@@ -78,30 +280,469 @@ impl RecvTask {
         self.set_freq(freq);
     }
 
+    /// Hand voice grants to the voice tuners with the given channels instead of following
+    /// them on this receiver's SDR.
+    pub fn set_voice_tuners(&mut self, tuners: Vec<Sender<RecvEvent>>) {
+        let pool = TunerPool::new(tuners.len());
+        self.role = RecvRole::Control(tuners, pool);
+    }
+
+    /// Only follow grants to channels between the given frequencies (Hz).
+    pub fn set_tuning_range(&mut self, min: u32, max: u32) {
+        self.range = (min, max);
+    }
+
+    /// Discard chunks received before each retune takes effect, as tracked by the given
+    /// tuning generation, along with the samples after it while the tuner settles if the
+    /// flag is set.
+    ///
+    /// A retune that's already been requested is waited for as well.
+    pub fn track_tuning(&mut self, generation: Arc<AtomicUsize>, settle: bool) {
+        self.settle = settle;
+
+        if self.curfreq != std::u32::MAX {
+            self.expect = generation.load(Ordering::SeqCst) + 1;
+            self.start_retune();
+        }
+
+        self.generation = Some(generation);
+    }
+
+    /// Start discarding samples for a retune.
+    fn start_retune(&mut self) {
+        self.retuned = Some(Instant::now());
+        self.discard = if self.settle { RETUNE_SETTLE } else { 0 };
+    }
+
+    /// Check if the given chunk was received after the latest retune took effect.
+    fn fresh(&mut self, stats: &ChunkStats, samples: usize) -> bool {
+        if self.generation.is_none() {
+            return true;
+        }
+
+        if stats.generation < self.expect {
+            return false;
+        }
+
+        // The generation also advances without a retune from this receiver when the SDR
+        // is reopened, leaving a gap in the samples.
+        if stats.generation != self.seen {
+            self.seen = stats.generation;
+            self.discard = if self.settle { RETUNE_SETTLE } else { 0 };
+            self.msg.recv.resync();
+        }
+
+        if let Some(start) = self.retuned.take() {
+            let elapsed = start.elapsed();
+            let ms = elapsed.as_secs() as f32 * 1.0e3 +
+                     elapsed.subsec_nanos() as f32 / 1.0e6;
+
+            // Voice tuners are reported by the control receiver.
+            if let RecvRole::Voice(..) = self.role {} else {
+                self.hub.send(HubEvent::UpdateRetuneLatency(ms))
+                    .expect("unable to send retune latency");
+            }
+        }
+
+        if self.discard > 0 {
+            self.discard = self.discard.saturating_sub(samples);
+            return false;
+        }
+
+        true
+    }
+
+    /// Correct the SDR's tuning error using measurements taken on the control channel.
+    pub fn enable_afc(&mut self) {
+        self.afc = Some(Afc::new(FM_DEVIATION));
+    }
+
+    /// Feed a chunk's measurements into the frequency correction loop.
+    fn update_afc(&mut self, stats: &ChunkStats, samples: usize) {
+        // Only the control channel is guaranteed to carry a signal.
+        if self.curfreq != self.ctlfreq {
+            return;
+        }
+
+        let est = match self.afc {
+            Some(ref mut afc) => match afc.feed(stats.dc, samples, self.ctlfreq) {
+                Some(est) => est,
+                None => return,
+            },
+            None => return,
+        };
+
+        if est.changed {
+            self.sdr.send(ControlTaskEvent::SetCorrection(est.correction))
+                .expect("unable to set correction in sdr");
+        }
+
+        self.hub.send(HubEvent::UpdateAfc(est.error, est.correction))
+            .expect("unable to send afc estimate");
+    }
+
+    /// Choose the tuner gain from the given gains (tenths of dB) by how well the control
+    /// channel decodes, setting it through the given channel.
+    pub fn enable_gain_opt(&mut self, gains: Vec<i32>, sdr: Sender<ControlTaskEvent>) {
+        let opt = GainOptimizer::new(gains);
+
+        sdr.send(ControlTaskEvent::SetGain(opt.gain())).expect("unable to set gain");
+
+        self.gainopt = Some((opt, sdr));
+    }
+
+    /// Feed a chunk's measurements into the gain optimizer.
+    fn update_gain(&mut self, stats: &ChunkStats, samples: usize) {
+        // Decode rates are only comparable on the control channel.
+        if self.curfreq != self.ctlfreq {
+            return;
+        }
+
+        let step = match self.gainopt {
+            Some((ref mut opt, ref sdr)) => match opt.feed(stats.power, samples) {
+                Some(step) => {
+                    sdr.send(ControlTaskEvent::SetGain(step.gain))
+                        .expect("unable to set gain");
+                    step
+                },
+                None => return,
+            },
+            None => return,
+        };
+
+        if let Some(m) = step.measured {
+            self.hub.send(HubEvent::UpdateGainMeasurement(m))
+                .expect("unable to send gain measurement");
+        }
+    }
+
+    /// Feed a chunk's measurements into the quality report.
+    fn update_quality(&mut self, stats: &ChunkStats, samples: usize) {
+        if let Some(q) = self.quality.feed(stats.snr, stats.spread, samples) {
+            self.hub.send(HubEvent::State(StateEvent::UpdateQuality(q)))
+                .expect("unable to send quality");
+        }
+    }
+
+    /// Track the carrier state of the given chunk, ending a voice call if its carrier has
+    /// gone away, and return whether the chunk should be decoded.
+    fn update_carrier(&mut self, stats: &ChunkStats, samples: usize) -> bool {
+        let was = self.carrier;
+        self.carrier = stats.carrier;
+
+        if stats.carrier {
+            // Whatever was partially decoded before the carrier dropped is garbage.
+            if !was {
+                self.msg.recv.resync();
+            }
+
+            self.quiet = 0;
+
+            return true;
+        }
+
+        let prev = self.quiet;
+        self.quiet += samples;
+
+        // The terminator may never be heard, so the call is ended once, as soon as the
+        // carrier has been gone long enough.
+        if prev < CARRIER_HANG && self.quiet >= CARRIER_HANG && self.on_voice() {
+            self.end_voice(false);
+        }
+
+        false
+    }
+
+    /// Abandon voice calls after the given time (ms) without a decoded message, or never
+    /// if 0, and stay on voice channels for the given time (ms) after each transmission
+    /// ends.
+    pub fn set_voice_timers(&mut self, watchdog: u32, hang: u32) {
+        self.watchdog = ms_samples(watchdog);
+        self.hang = ms_samples(hang);
+    }
+
+    /// Advance the voice channel timers by the given number of samples, leaving the
+    /// channel if either has run out.
+    fn update_timers(&mut self, samples: usize) {
+        if !self.on_voice() {
+            self.idle = 0;
+            self.hanging = None;
+            return;
+        }
+
+        if let Some(left) = self.hanging {
+            if left > samples {
+                self.hanging = Some(left - samples);
+            } else {
+                self.end_voice(false);
+            }
+
+            return;
+        }
+
+        self.idle += samples;
+
+        if self.watchdog > 0 && self.idle >= self.watchdog {
+            self.end_voice(false);
+        }
+    }
+
+    /// Handle the end of a transmission on the current channel, staying for the hang
+    /// time in case another transmission follows.
+    fn end_transmission(&mut self) {
+        if self.hang == 0 || !self.on_voice() {
+            self.end_voice(false);
+            return;
+        }
+
+        // Terminators are often repeated, which shouldn't extend the hang time.
+        if self.hanging.is_some() {
+            return;
+        }
+
+        self.hanging = Some(self.hang);
+
+        self.audio.send(AudioEvent::EndTransmission(self.source()))
+            .expect("unable to send end of transmission");
+    }
+
+    /// Check if this receiver is currently following a voice channel.
+    fn on_voice(&self) -> bool {
+        match self.role {
+            RecvRole::Single => self.curfreq != self.ctlfreq,
+            RecvRole::Control(..) => false,
+            RecvRole::Voice(..) => self.curfreq != std::u32::MAX,
+        }
+    }
+
+    /// Get the audio source for voice frames from this receiver.
+    fn source(&self) -> usize {
+        match self.role {
+            RecvRole::Voice(t, _) => t + 1,
+            _ => 0,
+        }
+    }
+
+    /// Finish following the current voice channel.
+    fn end_voice(&mut self, encrypted: bool) {
+        if let RecvRole::Single = self.role {
+            self.switch_control();
+            return;
+        }
+
+        // An idle voice tuner stays on its last channel, so the rest of the call may
+        // still be heard.
+        if self.curfreq == std::u32::MAX {
+            return;
+        }
+
+        if let RecvRole::Voice(t, ref control) = self.role {
+            self.curfreq = std::u32::MAX;
+
+            self.audio.send(AudioEvent::EndTransmission(t + 1))
+                .expect("unable to send end of transmission");
+            control.send(RecvEvent::VoiceReleased(t, encrypted))
+                .expect("unable to release voice tuner");
+        }
+    }
+
+    /// Follow the given grant on this voice tuner.
+    fn follow_grant(&mut self, freq: u32, tg: TalkGroup) {
+        self.curgroup = tg;
+        self.set_freq(freq);
+    }
+
+    /// Return the given voice tuner to the pool.
+    fn release_voice(&mut self, tuner: usize, encrypted: bool) {
+        let grant = match self.role {
+            RecvRole::Control(_, ref mut pool) => pool.release(tuner),
+            _ => return,
+        };
+
+        if let Some(Grant { talkgroup: TalkGroup::Other(x), .. }) = grant {
+            if encrypted {
+                self.encrypted.insert(x);
+            }
+        }
+
+        self.hub.send(HubEvent::UpdateVoiceTuner(tuner, None))
+            .expect("unable to send voice tuner");
+    }
+
+    /// Set the directory where recordings started without a path are placed.
+    pub fn set_record_dir(&mut self, dir: PathBuf) {
+        self.record_dir = dir;
+    }
+
+    /// Use the given recorder for I/Q recordings.
+    pub fn set_iq_recorder(&mut self, iq: IqRecorder) {
+        self.iq = Some(iq);
+    }
+
+    /// Start a recording of the given kind at the given path, or at a new timestamped
+    /// path in the recording directory, replacing any current recording of that kind.
+    pub fn start_recording(&mut self, kind: RecordKind, path: Option<PathBuf>)
+        -> std::io::Result<()>
+    {
+        let path = match path {
+            Some(p) => p,
+            None => self.record_path(kind),
+        };
+
+        match kind {
+            RecordKind::Baseband => {
+                let file = try!(File::create(&path));
+                let mut w = try!(BasebandWriter::new(BufWriter::new(file),
+                                                     BASEBAND_SAMPLE_RATE));
+
+                try!(w.write_retune(self.curfreq, self.curfreq != self.ctlfreq));
+
+                self.stop_baseband();
+                self.record = Some(w);
+            },
+            RecordKind::Iq => match self.iq {
+                Some(ref iq) => try!(iq.start(&path)),
+                None => return Err(std::io::Error::new(ErrorKind::Other,
+                                                       "I/Q recording unavailable")),
+            },
+        }
+
+        self.update_recording(kind, Some(path.to_string_lossy().into_owned()));
+
+        Ok(())
+    }
+
+    /// Stop any current recording of the given kind.
+    pub fn stop_recording(&mut self, kind: RecordKind) {
+        match kind {
+            RecordKind::Baseband => self.stop_baseband(),
+            RecordKind::Iq => if let Some(ref iq) = self.iq {
+                iq.stop();
+            },
+        }
+
+        self.update_recording(kind, None);
+    }
+
+    fn stop_baseband(&mut self) {
+        if let Some(mut w) = self.record.take() {
+            w.flush().expect("unable to flush baseband");
+        }
+    }
+
+    fn record_path(&self, kind: RecordKind) -> PathBuf {
+        // Recordings started within the same second still get their own files.
+        let stamp = UTC::now().format("%Y%m%d-%H%M%S%.3f");
+
+        self.record_dir.join(match kind {
+            RecordKind::Baseband => format!("p25rx-{}.baseband", stamp),
+            RecordKind::Iq => format!("p25rx-{}", stamp),
+        })
+    }
+
+    fn update_recording(&mut self, kind: RecordKind, path: Option<String>) {
+        match kind {
+            RecordKind::Baseband => self.recording.baseband = path,
+            RecordKind::Iq => self.recording.iq = path,
+        }
+
+        let state = self.recording.clone();
+
+        self.hub.send(HubEvent::State(StateEvent::UpdateRecording(state)))
+            .expect("unable to send recording state");
+    }
+
     fn set_freq(&mut self, freq: u32) {
         self.curfreq = freq;
 
-        self.hub.send(HubEvent::UpdateCurFreq(freq))
-            .expect("unable to send current frequency");
+        if let Some(ref mut w) = self.record {
+            w.write_retune(freq, freq != self.ctlfreq).expect("unable to write baseband");
+        }
+
+        // Voice tuners are reported by the control receiver.
+        if let RecvRole::Voice(..) = self.role {} else {
+            self.hub.send(HubEvent::UpdateCurFreq(freq))
+                .expect("unable to send current frequency");
+        }
+
         self.sdr.send(ControlTaskEvent::SetFreq(freq))
             .expect("unable to set freq in sdr");
 
+        // Each retune advances the generation once, including any still queued in the
+        // SDR.
+        let current = match self.generation {
+            Some(ref g) => Some(g.load(Ordering::SeqCst)),
+            None => None,
+        };
+
+        if let Some(current) = current {
+            self.expect = std::cmp::max(self.expect, current) + 1;
+            self.start_retune();
+        }
+
+        // Samples in flight are from the previous channel.
+        if let Some(ref mut afc) = self.afc {
+            afc.reset();
+        }
+
+        // Gain measurements only cover the control channel, so they're paused while
+        // following voice rather than started over after every call.
+        if freq == self.ctlfreq {
+            if let Some((ref mut opt, _)) = self.gainopt {
+                opt.resume();
+            }
+        }
+
+        self.quiet = 0;
+        self.idle = 0;
+        self.hanging = None;
+
+        if let Some(ref mut hunt) = self.hunt {
+            hunt.restart();
+        }
+
         self.msg.recv.resync();
     }
 
-    pub fn run<F>(&mut self, mut cb: F)
-        where F: FnMut(&[f32])
-    {
+    pub fn run(&mut self) {
         loop {
             match self.events.recv().expect("unable to receive baseband") {
-                RecvEvent::Baseband(samples) => {
-                    for &s in samples.iter() {
-                        self.handle_sample(s);
+                RecvEvent::Baseband(samples, stats) => {
+                    if !self.fresh(&stats, samples.len()) {
+                        continue;
                     }
 
-                    cb(&samples[..]);
+                    if self.update_carrier(&stats, samples.len()) {
+                        for &s in samples.iter() {
+                            self.handle_sample(s);
+                        }
+                    }
+
+                    self.update_timers(samples.len());
+                    self.update_hunt(samples.len());
+                    self.update_afc(&stats, samples.len());
+                    self.update_gain(&stats, samples.len());
+                    self.update_quality(&stats, samples.len());
+
+                    if let Some(ref mut w) = self.record {
+                        w.write_samples(&samples[..]).expect("unable to write baseband");
+                    }
+                },
+                RecvEvent::SetControlFreq(freq) => self.select_control_freq(freq),
+                RecvEvent::StartRecording(kind, reply) => {
+                    // Nobody is left to tell if the hub has gone away.
+                    let _ = reply.send(self.start_recording(kind, None));
+                },
+                RecvEvent::StopRecording(kind) => self.stop_recording(kind),
+                RecvEvent::VoiceGrant(freq, tg) => self.follow_grant(freq, tg),
+                RecvEvent::VoiceReleased(t, encrypted) =>
+                    self.release_voice(t, encrypted),
+                RecvEvent::StopGainOpt => self.gainopt = None,
+                RecvEvent::EndOfStream => {
+                    self.stop_recording(RecordKind::Baseband);
+                    self.stop_recording(RecordKind::Iq);
+                    return;
                 },
-                RecvEvent::SetControlFreq(freq) => self.set_control_freq(freq),
             }
         }
     }
@@ -114,16 +755,25 @@ impl RecvTask {
             None => return,
         };
 
+        if let Error(_) = event {
+            return;
+        }
+
+        self.idle = 0;
+
         match event {
-            Error(_) => {},
             PacketNID(nid) => {
                 match nid.data_unit {
                     DataUnit::VoiceLCTerminator | DataUnit::VoiceSimpleTerminator =>
-                        self.switch_control(),
+                        self.end_transmission(),
                     _ => {},
                 }
             },
-            VoiceHeader(head) => self.handle_crypto(head.crypto_alg()),
+            VoiceHeader(head) => {
+                // Another transmission started during the hang time.
+                self.hanging = None;
+                self.handle_crypto(head.crypto_alg());
+            },
             LinkControl(lc) => {
                 let _ = match lc.opcode() {
                     Some(o) => o,
@@ -136,7 +786,14 @@ impl RecvTask {
             CryptoControl(cc) => self.handle_crypto(cc.alg()),
             LowSpeedDataFragment(_) => {},
             VoiceFrame(vf) => {
-                self.audio.send(AudioEvent::VoiceFrame(vf))
+                self.quality.voice_frame(vf.errors);
+                self.hanging = None;
+
+                if self.curfreq == std::u32::MAX {
+                    return;
+                }
+
+                self.audio.send(AudioEvent::VoiceFrame(self.source(), vf))
                     .expect("unable to send voice frame");
             },
             TrunkingControl(tsbk) => {
@@ -144,10 +801,27 @@ impl RecvTask {
                     return;
                 }
 
-                if !tsbk.crc_valid() {
+                let valid = tsbk.crc_valid();
+                self.quality.tsbk(valid);
+
+                if self.curfreq == self.ctlfreq {
+                    if let Some((ref mut opt, _)) = self.gainopt {
+                        opt.tsbk(valid);
+                    }
+                }
+
+                if !valid {
                     return;
                 }
 
+                if let Some(ref mut afc) = self.afc {
+                    afc.mark_signal();
+                }
+
+                if let Some(ref mut hunt) = self.hunt {
+                    hunt.valid();
+                }
+
                 let opcode = match tsbk.opcode() {
                     Some(o) => o,
                     None => return,
@@ -162,8 +836,12 @@ impl RecvTask {
                                           .updates();
 
                         for (ch, tg) in updates.iter().cloned() {
+                            // With a single tuner only the first usable grant can be
+                            // followed.
                             if self.use_talkgroup(tg, ch) {
-                                break;
+                                if let RecvRole::Single = self.role {
+                                    break;
+                                }
                             }
                         }
                     },
@@ -174,10 +852,19 @@ impl RecvTask {
                             StateEvent::UpdateChannelParams(tsbk)
                         )).expect("unable to send channel update");
                     },
+                    TsbkOpcode::AltControlChannel => {
+                        let dec = fields::AltControlChannel::new(tsbk.payload());
+
+                        for &(ch, _) in dec.alts().iter() {
+                            self.add_alt_control(ch);
+                        }
+                    },
                     _ => {},
                 }
             }
             VoiceTerm(_) => {},
+            // Errors were skipped above.
+            _ => {},
         }
     }
 
@@ -186,13 +873,30 @@ impl RecvTask {
             return;
         }
 
-        self.switch_control();
+        self.end_voice(true);
 
         if let TalkGroup::Other(x) = self.curgroup {
             self.encrypted.insert(x);
         }
     }
 
+    /// Add the given alternate control channel announced by the site to the control
+    /// channel candidates, if it can be received.
+    fn add_alt_control(&mut self, ch: Channel) {
+        let freq = match self.channels.lookup(ch.id()) {
+            Some(p) => p.rx_freq(ch.number()),
+            None => return,
+        };
+
+        if freq < self.range.0 || freq > self.range.1 {
+            return;
+        }
+
+        if let Some(ref mut hunt) = self.hunt {
+            hunt.add(freq);
+        }
+    }
+
     fn use_talkgroup(&mut self, tg: TalkGroup, ch: Channel) -> bool {
         if let TalkGroup::Other(x) = tg {
             if self.encrypted.contains(&x) {
@@ -205,11 +909,38 @@ impl RecvTask {
             None => return false,
         };
 
-        self.curgroup = tg;
+        if freq < self.range.0 || freq > self.range.1 {
+            return false;
+        }
 
-        self.set_freq(freq);
-        self.hub.send(HubEvent::UpdateTalkGroup(tg))
-            .expect("unable to send talkgroup");
+        if let RecvRole::Single = self.role {
+            self.curgroup = tg;
+
+            self.set_freq(freq);
+            self.hub.send(HubEvent::UpdateTalkGroup(tg))
+                .expect("unable to send talkgroup");
+
+            return true;
+        }
+
+        let tuner = match self.role {
+            RecvRole::Single => unreachable!(),
+            RecvRole::Control(ref tuners, ref mut pool) => {
+                match pool.assign(Grant { freq: freq, talkgroup: tg }) {
+                    Assign::Assigned(t) => {
+                        tuners[t].send(RecvEvent::VoiceGrant(freq, tg))
+                            .expect("unable to send voice grant");
+                        t
+                    },
+                    Assign::Following(_) => return true,
+                    Assign::Busy => return false,
+                }
+            },
+            RecvRole::Voice(..) => return false,
+        };
+
+        self.hub.send(HubEvent::UpdateVoiceTuner(tuner, Some((freq, tg))))
+            .expect("unable to send voice tuner");
 
         true
     }
@@ -229,24 +960,26 @@ impl<W: Write> ReplayReceiver<W> {
     }
 
     pub fn replay<R: Read>(&mut self, stream: &mut R) {
-        let mut buf = [0; 32768];
-
-        loop {
-            let size = stream.read(&mut buf).expect("unable to read samples");
+        let mut reader = BasebandReader::new(stream).expect("unable to read baseband");
 
-            if size == 0 {
-                break;
+        if let Some(h) = reader.header() {
+            if h.rate != BASEBAND_SAMPLE_RATE {
+                panic!("unsupported baseband sample rate");
             }
+        }
 
-            let samples: &[f32] = unsafe {
-                std::slice::from_raw_parts(
-                    buf.as_ptr() as *const f32,
-                    size / std::mem::size_of::<f32>()
-                )
-            };
+        let mut samples = vec![];
 
-            self.feed(samples);
+        loop {
+            match reader.read_record(&mut samples).expect("unable to read samples") {
+                Some(Record::Samples) => self.feed(&samples[..]),
+                // Samples after a retune are unrelated to those before it.
+                Some(Record::Retune { .. }) => self.msg.recv.resync(),
+                None => break,
+            }
         }
+
+        self.audio.flush();
     }
 
     fn feed(&mut self, samples: &[f32]) {
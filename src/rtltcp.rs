@@ -0,0 +1,582 @@
+//! Client and server for the `rtl_tcp` network protocol.
+//!
+//! An `rtl_tcp` server streams raw unsigned 8-bit I/Q samples from a remote RTL-SDR,
+//! preceded by a small header describing the tuner, and accepts 5-byte commands (an
+//! opcode followed by a big-endian parameter) for adjusting the SDR.
+
+use std::io::{Read, Write, ErrorKind};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Sender, Receiver, SyncSender, TrySendError, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std;
+
+use consts::BUF_BYTES;
+use sdr::{SampleSource, SdrControl, ControlTaskEvent, TapEvent};
+
+/// Number of chunks buffered for each server client before chunks are dropped.
+const CLIENT_CHUNKS: usize = 16;
+
+/// Magic bytes at the start of the stream header.
+pub const MAGIC: &'static [u8; 4] = b"RTL0";
+
+/// Size of the stream header (bytes).
+pub const HEADER_BYTES: usize = 12;
+
+/// Size of a command (bytes).
+pub const COMMAND_BYTES: usize = 5;
+
+/// Command opcodes understood by `rtl_tcp`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Set center frequency (Hz).
+    SetFreq,
+    /// Set sample rate (Hz).
+    SetSampleRate,
+    /// Set tuner gain mode (0 for automatic, 1 for manual).
+    SetGainMode,
+    /// Set manual tuner gain (tenths of dB).
+    SetGain,
+    /// Set frequency correction (ppm).
+    SetFreqCorrection,
+    /// Set RTL2832 digital AGC (0 for off, 1 for on).
+    SetAgcMode,
+    /// Set direct sampling mode (0 for off, 1 for I branch, 2 for Q branch).
+    SetDirectSampling,
+    /// Set bias tee power (0 for off, 1 for on).
+    SetBiasTee,
+}
+
+impl Command {
+    /// Get the opcode byte for the command.
+    pub fn opcode(&self) -> u8 {
+        use self::Command::*;
+
+        match *self {
+            SetFreq => 0x01,
+            SetSampleRate => 0x02,
+            SetGainMode => 0x03,
+            SetGain => 0x04,
+            SetFreqCorrection => 0x05,
+            SetAgcMode => 0x08,
+            SetDirectSampling => 0x09,
+            SetBiasTee => 0x0e,
+        }
+    }
+
+    /// Try to parse the given opcode byte.
+    pub fn from_opcode(op: u8) -> Option<Self> {
+        use self::Command::*;
+
+        match op {
+            0x01 => Some(SetFreq),
+            0x02 => Some(SetSampleRate),
+            0x03 => Some(SetGainMode),
+            0x04 => Some(SetGain),
+            0x05 => Some(SetFreqCorrection),
+            0x08 => Some(SetAgcMode),
+            0x09 => Some(SetDirectSampling),
+            0x0e => Some(SetBiasTee),
+            _ => None,
+        }
+    }
+
+    /// Encode the command with the given parameter.
+    pub fn encode(&self, param: u32) -> [u8; COMMAND_BYTES] {
+        [
+            self.opcode(),
+            (param >> 24) as u8,
+            (param >> 16) as u8,
+            (param >> 8) as u8,
+            param as u8,
+        ]
+    }
+}
+
+/// Decode the opcode and parameter of the given command bytes.
+pub fn decode_command(buf: &[u8; COMMAND_BYTES]) -> (u8, u32) {
+    (buf[0], read_u32(&buf[1..]))
+}
+
+/// Tuner types reported in the stream header, matching librtlsdr's numbering.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TunerType {
+    Unknown,
+    E4000,
+    Fc0012,
+    Fc0013,
+    Fc2580,
+    R820t,
+    R828d,
+}
+
+impl TunerType {
+    /// Parse the given header value.
+    pub fn from_u32(t: u32) -> Self {
+        use self::TunerType::*;
+
+        match t {
+            1 => E4000,
+            2 => Fc0012,
+            3 => Fc0013,
+            4 => Fc2580,
+            5 => R820t,
+            6 => R828d,
+            _ => Unknown,
+        }
+    }
+
+    /// Get the header value for the tuner.
+    pub fn to_u32(&self) -> u32 {
+        use self::TunerType::*;
+
+        match *self {
+            Unknown => 0,
+            E4000 => 1,
+            Fc0012 => 2,
+            Fc0013 => 3,
+            Fc2580 => 4,
+            R820t => 5,
+            R828d => 6,
+        }
+    }
+
+    /// Determine the tuner that supports exactly the given gains (tenths of dB).
+    pub fn from_gains(gains: &[i32]) -> Self {
+        use self::TunerType::*;
+
+        for &t in [E4000, Fc0012, Fc0013, Fc2580, R820t].iter() {
+            if t.gains() == gains {
+                return t;
+            }
+        }
+
+        Unknown
+    }
+
+    /// Get the gains (tenths of dB) supported by the tuner.
+    ///
+    /// The stream header only carries the number of gains, so these tables are copied
+    /// from librtlsdr.
+    pub fn gains(&self) -> &'static [i32] {
+        use self::TunerType::*;
+
+        match *self {
+            Unknown => &[],
+            E4000 => &[-10, 15, 40, 65, 90, 115, 140, 165, 190, 215, 240, 290, 340, 420],
+            Fc0012 => &[-99, -40, 71, 179, 192],
+            Fc0013 => &[-99, -73, -65, -63, -60, -58, -54, 58, 61, 63, 65, 67, 68, 70, 71,
+                        179, 181, 182, 184, 186, 188, 191, 197],
+            Fc2580 => &[0],
+            R820t | R828d => &[0, 9, 14, 27, 37, 77, 87, 125, 144, 157, 166, 197, 207,
+                               229, 254, 280, 297, 328, 338, 364, 372, 386, 402, 421,
+                               434, 439, 445, 480, 496],
+        }
+    }
+}
+
+/// Stream header sent by the server when a client connects.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Type of tuner on the server's SDR.
+    pub tuner: TunerType,
+    /// Number of supported tuner gains.
+    pub gain_count: u32,
+}
+
+impl Header {
+    /// Read and verify a header from the given stream.
+    pub fn read<R: Read>(mut stream: R) -> std::io::Result<Self> {
+        let mut buf = [0; HEADER_BYTES];
+        try!(stream.read_exact(&mut buf[..]));
+
+        if &buf[..4] != &MAGIC[..] {
+            return Err(std::io::Error::new(ErrorKind::InvalidData,
+                                           "invalid rtl_tcp header"));
+        }
+
+        Ok(Header {
+            tuner: TunerType::from_u32(read_u32(&buf[4..8])),
+            gain_count: read_u32(&buf[8..12]),
+        })
+    }
+
+    /// Write the header into the given stream.
+    pub fn write<W: Write>(&self, mut stream: W) -> std::io::Result<()> {
+        let mut buf = [0; HEADER_BYTES];
+
+        (&mut buf[..4]).copy_from_slice(&MAGIC[..]);
+        write_u32(&mut buf[4..8], self.tuner.to_u32());
+        write_u32(&mut buf[8..12], self.gain_count);
+
+        stream.write_all(&buf[..])
+    }
+}
+
+/// Connect to the `rtl_tcp` server at the given address, returning the control and
+/// sample stream halves of the connection.
+pub fn connect<A: ToSocketAddrs>(addr: A)
+    -> std::io::Result<(RtlTcpControl, RtlTcpReader)>
+{
+    let mut stream = try!(TcpStream::connect(addr));
+    try!(stream.set_nodelay(true));
+
+    let header = try!(Header::read(&mut stream));
+
+    Ok((RtlTcpControl {
+        stream: try!(stream.try_clone()),
+        tuner: header.tuner,
+    }, RtlTcpReader {
+        stream: stream,
+    }))
+}
+
+/// Sends commands to an `rtl_tcp` server.
+pub struct RtlTcpControl {
+    /// Connection to the server.
+    stream: TcpStream,
+    /// Type of tuner on the server's SDR.
+    tuner: TunerType,
+}
+
+impl RtlTcpControl {
+    /// Send the given command and parameter.
+    pub fn send(&mut self, cmd: Command, param: u32) -> std::io::Result<()> {
+        self.stream.write_all(&cmd.encode(param)[..])
+    }
+}
+
+impl SdrControl for RtlTcpControl {
+    fn set_center_freq(&mut self, freq: u32) -> std::io::Result<()> {
+        self.send(Command::SetFreq, freq)
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) -> std::io::Result<()> {
+        self.send(Command::SetSampleRate, rate)
+    }
+
+    fn set_ppm(&mut self, ppm: i32) -> std::io::Result<()> {
+        self.send(Command::SetFreqCorrection, ppm as u32)
+    }
+
+    fn set_tuner_gain(&mut self, gain: i32) -> std::io::Result<()> {
+        try!(self.send(Command::SetGainMode, 1));
+        self.send(Command::SetGain, gain as u32)
+    }
+
+    fn enable_agc(&mut self) -> std::io::Result<()> {
+        self.send(Command::SetGainMode, 0)
+    }
+
+    fn gains(&mut self) -> Vec<i32> {
+        self.tuner.gains().to_vec()
+    }
+
+    fn set_direct_sampling(&mut self, mode: u32) -> std::io::Result<()> {
+        self.send(Command::SetDirectSampling, mode)
+    }
+
+    fn set_bias_tee(&mut self, on: bool) -> std::io::Result<()> {
+        self.send(Command::SetBiasTee, on as u32)
+    }
+}
+
+/// Receives the sample stream from an `rtl_tcp` server.
+pub struct RtlTcpReader {
+    /// Connection to the server.
+    stream: TcpStream,
+}
+
+impl SampleSource for RtlTcpReader {
+    fn read_chunks<F: FnMut(&[u8])>(&mut self, mut cb: F) -> std::io::Result<()> {
+        let mut buf = vec![0; BUF_BYTES];
+
+        loop {
+            try!(self.stream.read_exact(&mut buf[..]));
+            cb(&buf[..]);
+        }
+    }
+}
+
+/// How the server handles commands from its clients.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TunePolicy {
+    /// Ignore all commands, leaving the SDR to the receiver.
+    Deny,
+    /// Forward frequency changes to the SDR, where they override the receiver's current
+    /// channel until it next hops, along with gain, ppm, direct sampling, and bias tee
+    /// changes.
+    Allow,
+}
+
+/// Convert the given client command and parameter to a message for the SDR, if it's
+/// one that's forwarded.
+pub fn command_event(cmd: Command, param: u32) -> Option<ControlTaskEvent> {
+    use self::Command::*;
+
+    match cmd {
+        SetFreq => Some(ControlTaskEvent::SetFreq(param)),
+        SetGainMode => Some(ControlTaskEvent::SetAgc(param == 0)),
+        SetGain => Some(ControlTaskEvent::SetGain(param as i32)),
+        SetFreqCorrection => Some(ControlTaskEvent::SetPpm(param as i32)),
+        SetDirectSampling => Some(ControlTaskEvent::SetDirectSampling(param)),
+        SetBiasTee => Some(ControlTaskEvent::SetBiasTee(param != 0)),
+        // The stream must stay at the receiver's sample rate.
+        SetSampleRate | SetAgcMode => None,
+    }
+}
+
+/// Republishes raw SDR chunks to `rtl_tcp` clients.
+pub struct ServerTask {
+    /// Socket for accepting clients.
+    listener: TcpListener,
+    /// Header sent to each client.
+    header: Header,
+    /// Handling of client commands.
+    policy: TunePolicy,
+    /// Channel for receiving raw chunks.
+    chunks: Receiver<TapEvent>,
+    /// Channel for forwarding client commands to the SDR.
+    sdr: Sender<ControlTaskEvent>,
+}
+
+impl ServerTask {
+    /// Create a new `ServerTask` accepting clients on the given socket, describing the
+    /// SDR with the given tuner gains, and republishing chunks from the given channel.
+    pub fn new(listener: TcpListener,
+               gains: &[i32],
+               policy: TunePolicy,
+               chunks: Receiver<TapEvent>,
+               sdr: Sender<ControlTaskEvent>)
+        -> Self
+    {
+        ServerTask {
+            listener: listener,
+            header: Header {
+                tuner: TunerType::from_gains(gains),
+                gain_count: gains.len() as u32,
+            },
+            policy: policy,
+            chunks: chunks,
+            sdr: sdr,
+        }
+    }
+
+    /// Begin serving clients, blocking the current thread.
+    pub fn run(&mut self) {
+        let clients = Arc::new(Mutex::new(Vec::<SyncSender<Arc<Vec<u8>>>>::new()));
+
+        {
+            let listener = self.listener.try_clone().expect("unable to clone listener");
+            let clients = clients.clone();
+            let header = self.header;
+            let policy = self.policy;
+            let sdr = self.sdr.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(s) => s,
+                        Err(_) => continue,
+                    };
+
+                    if let Ok(chan) = serve_client(stream, header, policy, sdr.clone()) {
+                        clients.lock().unwrap().push(chan);
+                    }
+                }
+            });
+        }
+
+        for event in self.chunks.iter() {
+            let chunk = match event {
+                TapEvent::Samples(chunk) => chunk,
+                TapEvent::Tuned(..) | TapEvent::Configured(..) => continue,
+            };
+
+            // Slow clients miss chunks rather than stalling the SDR.
+            clients.lock().unwrap().retain(|c| match c.try_send(chunk.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        }
+    }
+}
+
+/// Start streaming to the given client, returning a channel for its chunks.
+fn serve_client(mut stream: TcpStream, header: Header, policy: TunePolicy,
+                sdr: Sender<ControlTaskEvent>)
+    -> std::io::Result<SyncSender<Arc<Vec<u8>>>>
+{
+    try!(header.write(&mut stream));
+
+    let (tx, rx) = sync_channel::<Arc<Vec<u8>>>(CLIENT_CHUNKS);
+    let mut cmds = try!(stream.try_clone());
+
+    thread::spawn(move || {
+        for chunk in rx.iter() {
+            if stream.write_all(&chunk[..]).is_err() {
+                break;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let mut buf = [0; COMMAND_BYTES];
+
+        while cmds.read_exact(&mut buf[..]).is_ok() {
+            if policy == TunePolicy::Deny {
+                continue;
+            }
+
+            let (op, param) = decode_command(&buf);
+
+            let event = Command::from_opcode(op).and_then(|c| command_event(c, param));
+
+            let event = match event {
+                Some(e) => e,
+                None => continue,
+            };
+
+            if sdr.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(tx)
+}
+
+/// Read a big-endian word from the first 4 bytes of the given buffer.
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+/// Write the given word in big-endian order into the first 4 bytes of the given buffer.
+fn write_u32(buf: &mut [u8], w: u32) {
+    buf[0] = (w >> 24) as u8;
+    buf[1] = (w >> 16) as u8;
+    buf[2] = (w >> 8) as u8;
+    buf[3] = w as u8;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn test_command() {
+        let buf = Command::SetFreq.encode(851_012_500);
+        assert_eq!(buf, [0x01, 0x32, 0xb9, 0x6b, 0x94]);
+        assert_eq!(decode_command(&buf), (0x01, 851_012_500));
+        assert_eq!(Command::from_opcode(0x01), Some(Command::SetFreq));
+
+        let buf = Command::SetFreqCorrection.encode(-2i32 as u32);
+        assert_eq!(decode_command(&buf).1 as i32, -2);
+    }
+
+    #[test]
+    fn test_command_event() {
+        match command_event(Command::SetFreqCorrection, -2i32 as u32) {
+            Some(ControlTaskEvent::SetPpm(-2)) => {},
+            _ => panic!(),
+        }
+
+        match command_event(Command::SetGainMode, 0) {
+            Some(ControlTaskEvent::SetAgc(true)) => {},
+            _ => panic!(),
+        }
+
+        match command_event(Command::SetGain, 496) {
+            Some(ControlTaskEvent::SetGain(496)) => {},
+            _ => panic!(),
+        }
+
+        assert!(command_event(Command::SetSampleRate, 2_400_000).is_none());
+    }
+
+    #[test]
+    fn test_client() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let (tx, rx) = channel();
+
+        // Stand-in for rtl_tcp that sends two chunks of samples then records commands.
+        thread::spawn(move || {
+            let (mut s, _) = server.accept().unwrap();
+
+            Header { tuner: TunerType::R820t, gain_count: 29 }.write(&mut s).unwrap();
+
+            for i in 0..2 {
+                s.write_all(&vec![i as u8; BUF_BYTES][..]).unwrap();
+            }
+
+            let mut buf = [0; COMMAND_BYTES];
+
+            for _ in 0..4 {
+                s.read_exact(&mut buf[..]).unwrap();
+                tx.send(decode_command(&buf)).unwrap();
+            }
+        });
+
+        let (mut control, mut reader) = connect(addr).unwrap();
+
+        assert_eq!(control.gains().len(), 29);
+
+        control.set_center_freq(851_012_500).unwrap();
+        control.set_tuner_gain(297).unwrap();
+        control.set_ppm(-2).unwrap();
+
+        assert_eq!(rx.recv().unwrap(), (0x01, 851_012_500));
+        assert_eq!(rx.recv().unwrap(), (0x03, 1));
+        assert_eq!(rx.recv().unwrap(), (0x04, 297));
+        assert_eq!(rx.recv().unwrap(), (0x05, -2i32 as u32));
+
+        let mut chunks = vec![];
+        assert!(reader.read_chunks(|c| chunks.push(c[0])).is_err());
+        assert_eq!(chunks, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx_chunks, rx_chunks) = channel();
+        let (tx_sdr, rx_sdr) = channel();
+
+        let mut server = ServerTask::new(listener, TunerType::R820t.gains(),
+                                         TunePolicy::Allow, rx_chunks, tx_sdr);
+
+        thread::spawn(move || server.run());
+
+        let (mut control, mut reader) = connect(addr).unwrap();
+
+        assert_eq!(control.gains().len(), 29);
+
+        // Keep publishing until the client has been registered.
+        thread::spawn(move || {
+            for _ in 0..500 {
+                let chunk = TapEvent::Samples(Arc::new(vec![42; BUF_BYTES]));
+
+                if tx_chunks.send(chunk).is_err() {
+                    break;
+                }
+
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+        });
+
+        let mut buf = vec![0; BUF_BYTES];
+        reader.stream.read_exact(&mut buf[..]).unwrap();
+        assert!(buf.iter().all(|&b| b == 42));
+
+        control.set_center_freq(851_012_500).unwrap();
+
+        match rx_sdr.recv().unwrap() {
+            ControlTaskEvent::SetFreq(f) => assert_eq!(f, 851_012_500),
+            _ => panic!(),
+        }
+    }
+}
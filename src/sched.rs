@@ -0,0 +1,105 @@
+//! Assignment of voice grants to dedicated voice tuners.
+
+use p25::trunking::fields::TalkGroup;
+
+/// Voice channel being followed by a tuner.
+#[derive(Copy, Clone)]
+pub struct Grant {
+    /// Channel frequency (Hz).
+    pub freq: u32,
+    /// Talkgroup the channel was granted to.
+    pub talkgroup: TalkGroup,
+}
+
+/// Result of offering a grant to a `TunerPool`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Assign {
+    /// The channel was already being followed by the contained tuner.
+    Following(usize),
+    /// The contained tuner was free and is now assigned to the channel.
+    Assigned(usize),
+    /// Every tuner is following some other channel.
+    Busy,
+}
+
+/// Tracks which voice tuners are following which channels.
+pub struct TunerPool {
+    /// Grant followed by each tuner, or `None` if the tuner is free.
+    tuners: Vec<Option<Grant>>,
+}
+
+impl TunerPool {
+    /// Create a new `TunerPool` with the given number of free tuners.
+    pub fn new(count: usize) -> Self {
+        TunerPool {
+            tuners: vec![None; count],
+        }
+    }
+
+    /// Offer the given grant, assigning it to the lowest numbered free tuner if no tuner
+    /// is already following its channel.
+    ///
+    /// Grants are repeated on the control channel for the length of a call, so a repeated
+    /// grant maps to the tuner that took the original.
+    pub fn assign(&mut self, grant: Grant) -> Assign {
+        if let Some(t) = self.tuners.iter().position(|g| match *g {
+            Some(g) => g.freq == grant.freq,
+            None => false,
+        }) {
+            return Assign::Following(t);
+        }
+
+        match self.tuners.iter().position(|g| g.is_none()) {
+            Some(t) => {
+                self.tuners[t] = Some(grant);
+                Assign::Assigned(t)
+            },
+            None => Assign::Busy,
+        }
+    }
+
+    /// Free the given tuner, returning the grant it was following.
+    pub fn release(&mut self, tuner: usize) -> Option<Grant> {
+        self.tuners[tuner].take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use p25::trunking::fields::TalkGroup;
+
+    fn grant(freq: u32) -> Grant {
+        Grant {
+            freq: freq,
+            talkgroup: TalkGroup::Other(freq as u16),
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let mut p = TunerPool::new(2);
+
+        assert_eq!(p.assign(grant(851000000)), Assign::Assigned(0));
+        assert_eq!(p.assign(grant(851000000)), Assign::Following(0));
+        assert_eq!(p.assign(grant(852000000)), Assign::Assigned(1));
+        assert_eq!(p.assign(grant(853000000)), Assign::Busy);
+        assert_eq!(p.assign(grant(852000000)), Assign::Following(1));
+
+        assert_eq!(p.release(0).unwrap().freq, 851000000);
+        assert!(p.release(0).is_none());
+        // Releasing one tuner leaves the other on its channel.
+        assert_eq!(p.assign(grant(852000000)), Assign::Following(1));
+
+        assert_eq!(p.assign(grant(853000000)), Assign::Assigned(0));
+        assert_eq!(p.assign(grant(851000000)), Assign::Busy);
+        assert_eq!(p.release(1).unwrap().freq, 852000000);
+        assert_eq!(p.release(0).unwrap().freq, 853000000);
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut p = TunerPool::new(0);
+        assert_eq!(p.assign(grant(851000000)), Assign::Busy);
+    }
+}
@@ -1,35 +1,287 @@
 //! Interface to RTL-SDR.
 
-use std::sync::mpsc::{Sender, Receiver};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, Receiver, channel};
+use std::thread;
+use std::time::Duration;
+use std;
 
+use mio;
 use pool::{Pool, Checkout};
-use rtlsdr::{Controller, Reader};
+use rtlsdr::{Controller, Reader, TunerGains};
 
-use consts::{BUF_BYTES, BUF_COUNT};
+use consts::{BUF_BYTES, BUF_COUNT, SDR_SAMPLE_RATE};
+use hub::{HubEvent, StateEvent};
+
+/// Delay (seconds) before the first attempt to reopen a lost SDR.
+const REOPEN_DELAY: u64 = 1;
+
+/// Longest delay (seconds) between attempts to reopen a lost SDR.
+const REOPEN_MAX_DELAY: u64 = 30;
+
+/// Source of interleaved unsigned 8-bit I/Q samples.
+pub trait SampleSource {
+    /// Pass successive chunks of `BUF_BYTES` bytes to the given callback, blocking until
+    /// the source is exhausted.
+    fn read_chunks<F: FnMut(&[u8])>(&mut self, cb: F) -> std::io::Result<()>;
+}
+
+impl SampleSource for Reader {
+    fn read_chunks<F: FnMut(&[u8])>(&mut self, cb: F) -> std::io::Result<()> {
+        self.read_async(BUF_COUNT as u32, BUF_BYTES as u32, cb).map_err(driver_err)
+    }
+}
+
+/// Adjustable parameters of a sample source.
+pub trait SdrControl {
+    /// Set the center frequency (Hz).
+    fn set_center_freq(&mut self, freq: u32) -> std::io::Result<()>;
+    /// Set the sample rate (Hz).
+    fn set_sample_rate(&mut self, rate: u32) -> std::io::Result<()>;
+    /// Set the frequency correction (ppm).
+    fn set_ppm(&mut self, ppm: i32) -> std::io::Result<()>;
+    /// Set a manual tuner gain (tenths of dB).
+    fn set_tuner_gain(&mut self, gain: i32) -> std::io::Result<()>;
+    /// Enable the tuner's automatic gain control.
+    fn enable_agc(&mut self) -> std::io::Result<()>;
+    /// Get the tuner gains (tenths of dB) that can be set.
+    fn gains(&mut self) -> Vec<i32>;
+
+    /// Set the direct sampling mode (0 for off, 1 for the I branch, 2 for the Q branch).
+    fn set_direct_sampling(&mut self, _mode: u32) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    /// Switch power to the bias tee on or off.
+    fn set_bias_tee(&mut self, _on: bool) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    /// Check if tuning is only emulated over samples that were already captured, which a
+    /// frequency correction can't move.
+    fn emulated(&self) -> bool { false }
+}
+
+impl SdrControl for Controller {
+    fn set_center_freq(&mut self, freq: u32) -> std::io::Result<()> {
+        Controller::set_center_freq(self, freq).map_err(driver_err)
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) -> std::io::Result<()> {
+        Controller::set_sample_rate(self, rate).map_err(driver_err)
+    }
+
+    fn set_ppm(&mut self, ppm: i32) -> std::io::Result<()> {
+        Controller::set_ppm(self, ppm).map_err(driver_err)
+    }
+
+    fn set_tuner_gain(&mut self, gain: i32) -> std::io::Result<()> {
+        Controller::set_tuner_gain(self, gain).map_err(driver_err)
+    }
+
+    fn enable_agc(&mut self) -> std::io::Result<()> {
+        Controller::enable_agc(self).map_err(driver_err)
+    }
+
+    fn gains(&mut self) -> Vec<i32> {
+        let mut gains = TunerGains::default();
+        self.tuner_gains(&mut gains).to_vec()
+    }
+
+    fn set_direct_sampling(&mut self, mode: u32) -> std::io::Result<()> {
+        Controller::set_direct_sampling(self, mode).map_err(driver_err)
+    }
+
+    fn set_bias_tee(&mut self, on: bool) -> std::io::Result<()> {
+        Controller::set_bias_tee(self, on).map_err(driver_err)
+    }
+}
+
+/// Opens an SDR, giving its control and sample source.
+pub type Opener<C, S> = Box<FnMut() -> std::io::Result<(C, S)> + Send>;
+
+/// Control for sources, such as recorded files, that have nothing to adjust.
+pub struct NoControl;
+
+impl SdrControl for NoControl {
+    fn set_center_freq(&mut self, _: u32) -> std::io::Result<()> { Ok(()) }
+    fn set_sample_rate(&mut self, _: u32) -> std::io::Result<()> { Ok(()) }
+    fn set_ppm(&mut self, _: i32) -> std::io::Result<()> { Ok(()) }
+    fn set_tuner_gain(&mut self, _: i32) -> std::io::Result<()> { Ok(()) }
+    fn enable_agc(&mut self) -> std::io::Result<()> { Ok(()) }
+    fn gains(&mut self) -> Vec<i32> { vec![] }
+}
+
+/// Slowest supported SDR sample rate (Hz) above the normal rate.
+pub const MIN_FAST_RATE: u32 = 960_000;
+
+/// Fastest supported SDR sample rate (Hz).
+pub const MAX_FAST_RATE: u32 = 3_120_000;
+
+/// Check if the given SDR sample rate (Hz) is supported: it must be a multiple of the
+/// normal SDR sample rate, so it can be decimated to baseband, within one of the ranges
+/// RTL-SDR dongles can sample at.
+///
+/// That leaves the normal rate and every multiple of it from `MIN_FAST_RATE` to
+/// `MAX_FAST_RATE`, so common rates such as 2048000 aren't supported.
+pub fn valid_sample_rate(rate: u32) -> bool {
+    rate % SDR_SAMPLE_RATE == 0 &&
+        (rate > 225_000 && rate <= 300_000 || rate > 900_000 && rate <= 3_200_000)
+}
+
+/// Convert an error from the RTL-SDR driver to an I/O error.
+fn driver_err<E>(_: E) -> std::io::Error {
+    std::io::Error::new(ErrorKind::Other, "rtlsdr driver error")
+}
+
+/// Create an error for a setting the SDR doesn't support.
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidInput, "setting not supported by sdr")
+}
+
+/// Current SDR settings.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SdrSettings {
+    /// Manual tuner gain (tenths of dB), or `None` if the tuner AGC is enabled.
+    pub gain: Option<i32>,
+    /// Frequency correction (ppm).
+    pub ppm: i32,
+    /// Direct sampling mode.
+    #[serde(rename = "directSampling")]
+    pub direct_sampling: u32,
+    /// Whether the bias tee is powered.
+    #[serde(rename = "biasTee")]
+    pub bias_tee: bool,
+    /// Tuner gains (tenths of dB) that can be set.
+    pub gains: Vec<i32>,
+    /// Sample rate (Hz).
+    #[serde(rename = "sampleRate")]
+    pub sample_rate: u32,
+}
+
+/// Events seen by consumers tapping into the raw SDR stream.
+#[derive(Clone)]
+pub enum TapEvent {
+    /// A chunk of raw samples was read.
+    Samples(Arc<Vec<u8>>),
+    /// The SDR was retuned to the contained center frequency (Hz), starting the
+    /// contained tuning generation.
+    Tuned(u32, usize),
+    /// The SDR settings or the fine frequency correction (ppm) applied on top of them
+    /// changed to the contained values.
+    Configured(SdrSettings, f32),
+}
+
+/// Chunk of raw samples read from the SDR.
+pub struct SampleChunk {
+    /// Interleaved I/Q bytes.
+    pub bytes: Checkout<Vec<u8>>,
+    /// Tuning generation the SDR was at when the chunk was read.
+    pub generation: usize,
+}
 
 /// Reads chunks of samples from the SDR and sends them over a channel.
 pub struct ReadTask {
     /// Channel to send chunks over.
-    chan: Sender<Checkout<Vec<u8>>>,
+    chan: Sender<SampleChunk>,
+    /// Additional consumers of the raw chunks, each along with a flag that's set while
+    /// it wants chunks, if it doesn't always.
+    taps: Vec<(Sender<TapEvent>, Option<Arc<AtomicBool>>)>,
+    /// Tuning generation, advanced by the `ControlTask` of the same SDR.
+    generation: Arc<AtomicUsize>,
 }
 
 impl ReadTask {
-    /// Create a new `ReadTask` communicating over the given channel.
-    pub fn new(chan: Sender<Checkout<Vec<u8>>>) -> Self {
+    /// Create a new `ReadTask` communicating over the given channel, tagging chunks with
+    /// the given tuning generation.
+    pub fn new(chan: Sender<SampleChunk>, generation: Arc<AtomicUsize>) -> Self {
         ReadTask {
             chan: chan,
+            taps: vec![],
+            generation: generation,
         }
     }
 
-    /// Start reading samples, blocking the thread.
-    pub fn run(&mut self, mut reader: Reader) {
+    /// Send a copy of each chunk over the given channel in addition to the main one.
+    ///
+    /// Chunks are dropped without error if the receiving end disconnects.
+    pub fn add_tap(&mut self, tap: Sender<TapEvent>) {
+        self.taps.push((tap, None));
+    }
+
+    /// Send a copy of each chunk over the given channel while the given flag is set.
+    ///
+    /// Chunks aren't copied at all while no tap wants them.
+    pub fn add_gated_tap(&mut self, tap: Sender<TapEvent>, active: Arc<AtomicBool>) {
+        self.taps.push((tap, Some(active)));
+    }
+
+    /// Start reading samples from the given source, blocking the thread until the source
+    /// is exhausted.
+    pub fn run<S: SampleSource>(&mut self, source: S) {
         let mut pool = Pool::with_capacity(16, || vec![0; BUF_BYTES]);
 
-        reader.read_async(BUF_COUNT as u32, BUF_BYTES as u32, |bytes| {
+        self.read(&mut pool, source).expect("error in async read");
+    }
+
+    /// Start reading samples from the given source, which is never expected to be
+    /// exhausted, blocking the thread.
+    ///
+    /// When the source stops, the loss is sent over the first given channel, and reading
+    /// resumes from the replacement source received on the second given channel.
+    pub fn run_reopening<S: SampleSource>(&mut self, mut source: S,
+                                          lost: Sender<ControlTaskEvent>,
+                                          sources: Receiver<S>)
+    {
+        let mut pool = Pool::with_capacity(16, || vec![0; BUF_BYTES]);
+
+        loop {
+            // A live SDR only stops reading if something went wrong, whether or not an
+            // error was returned.
+            let _ = self.read(&mut pool, source);
+
+            lost.send(ControlTaskEvent::DeviceLost).expect("unable to report lost sdr");
+
+            source = match sources.recv() {
+                Ok(s) => s,
+                Err(_) => return,
+            };
+        }
+    }
+
+    /// Read samples from the given source until it stops.
+    fn read<S: SampleSource>(&mut self, pool: &mut Pool<Vec<u8>>, mut source: S)
+        -> std::io::Result<()>
+    {
+        source.read_chunks(|bytes| {
             let mut samples = pool.checkout().expect("unable to allocate samples");
             (&mut samples[..]).copy_from_slice(bytes);
-            self.chan.send(samples).expect("unable to send sdr samples");
-        }).expect("error in async read");
+
+            self.chan.send(SampleChunk {
+                bytes: samples,
+                generation: self.generation.load(Ordering::SeqCst),
+            }).expect("unable to send sdr samples");
+
+            let mut shared = None;
+
+            for &(ref tap, ref active) in self.taps.iter() {
+                if let Some(ref a) = *active {
+                    if !a.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                }
+
+                // Copy the chunk once for all the taps that want it.
+                if shared.is_none() {
+                    shared = Some(Arc::new(bytes.to_vec()));
+                }
+
+                let chunk = shared.as_ref().unwrap().clone();
+                let _ = tap.send(TapEvent::Samples(chunk));
+            }
+        })
     }
 }
 
@@ -37,33 +289,375 @@ impl ReadTask {
 pub enum ControlTaskEvent {
     /// Set the center frequency to the contained value (Hz).
     SetFreq(u32),
+    /// Set the fine frequency correction (ppm) applied to tuned frequencies on top of the
+    /// SDR's own ppm setting.
+    SetCorrection(f32),
+    /// Set a manual tuner gain (tenths of dB), disabling the tuner AGC.
+    SetGain(i32),
+    /// Enable the tuner AGC, or disable it and return to the last manual gain.
+    SetAgc(bool),
+    /// Set the SDR's frequency correction (ppm).
+    SetPpm(i32),
+    /// Set the direct sampling mode.
+    SetDirectSampling(u32),
+    /// Switch power to the bias tee on or off.
+    SetBiasTee(bool),
+    /// The SDR stopped delivering samples and needs to be reopened.
+    DeviceLost,
+    /// Apply the contained changes in order, stopping at the first that fails, and
+    /// report the result over the given channel.
+    Apply(Vec<ControlTaskEvent>, mio::channel::Sender<std::io::Result<()>>),
 }
 
 /// Controls SDR parameters.
-pub struct ControlTask {
-    /// SDR interface.
-    sdr: Controller,
+pub struct ControlTask<C: SdrControl> {
+    /// SDR interface, or `None` while the SDR is being reopened.
+    sdr: Option<C>,
     /// Channel for messages.
     events: Receiver<ControlTaskEvent>,
+    /// Consumers notified of retunes and setting changes.
+    taps: Vec<Sender<TapEvent>>,
+    /// Current frequency (Hz), before correction.
+    freq: Option<u32>,
+    /// Fine frequency correction (ppm).
+    correction: f32,
+    /// Offset (Hz) the SDR is tuned from each requested frequency.
+    offset: i32,
+    /// Current settings, if they're being reported.
+    settings: Option<SdrSettings>,
+    /// Most recent manual gain (tenths of dB), restored when AGC is disabled.
+    manual_gain: i32,
+    /// Channel for reporting settings.
+    hub: Option<mio::channel::Sender<HubEvent>>,
+    /// Tuning generation, advanced after each retune.
+    generation: Arc<AtomicUsize>,
+    /// Reopens the SDR, handing its sample source to the `ReadTask`, if the SDR can be
+    /// recovered when lost.
+    reopen: Option<Box<FnMut() -> std::io::Result<C> + Send>>,
 }
 
-impl ControlTask {
+impl<C: SdrControl> ControlTask<C> {
     /// Create a new `ControlTask` over the given SDR, receiving messages from the given
-    /// channel.
-    pub fn new(sdr: Controller, events: Receiver<ControlTaskEvent>) -> Self {
+    /// channel and advancing the given tuning generation after each retune.
+    pub fn new(sdr: C, events: Receiver<ControlTaskEvent>, generation: Arc<AtomicUsize>)
+        -> Self
+    {
         ControlTask {
-            sdr: sdr,
+            sdr: Some(sdr),
             events: events,
+            taps: vec![],
+            freq: None,
+            correction: 0.0,
+            offset: 0,
+            settings: None,
+            manual_gain: 0,
+            hub: None,
+            generation: generation,
+            reopen: None,
         }
     }
 
+    /// Track the given settings, which have already been applied to the SDR, so they can
+    /// be reported and restored.
+    pub fn set_settings(&mut self, settings: SdrSettings) {
+        self.manual_gain = match settings.gain {
+            Some(g) => g,
+            None => settings.gains.first().cloned().unwrap_or(0),
+        };
+
+        self.settings = Some(settings);
+    }
+
+    /// Report the current settings, each later change to them, and the status of the
+    /// SDR over the given hub.
+    pub fn set_hub(&mut self, hub: mio::channel::Sender<HubEvent>) {
+        self.hub = Some(hub);
+    }
+
+    /// Recover from losing the SDR by reopening it with the given function, returning the
+    /// channel the `ReadTask` receives each new sample source on.
+    pub fn set_reopen<S>(&mut self, mut open: Opener<C, S>) -> Receiver<S>
+        where C: 'static, S: Send + 'static
+    {
+        let (tx, rx) = channel();
+
+        self.reopen = Some(Box::new(move || {
+            let (control, source) = try!(open());
+
+            try!(tx.send(source).map_err(|_| {
+                std::io::Error::new(ErrorKind::Other, "sdr reader stopped")
+            }));
+
+            Ok(control)
+        }));
+
+        rx
+    }
+
+    /// Tune the SDR the given offset (Hz) away from each requested frequency, keeping the
+    /// SDR's DC spike off the channel.
+    pub fn set_offset(&mut self, offset: i32) {
+        self.offset = offset;
+    }
+
+    /// Notify the given channel each time the SDR is retuned or its settings change.
+    ///
+    /// Notifications are dropped without error if the receiving end disconnects.
+    pub fn add_tap(&mut self, tap: Sender<TapEvent>) {
+        self.taps.push(tap);
+    }
+
     /// Start managing the SDR, blocking the thread.
     pub fn run(&mut self) {
+        self.report();
+        self.configure();
+
+        loop {
+            let event = self.events.recv().expect("unable to receive controller event");
+
+            // Changes the SDR rejects are left out of the reported settings.
+            let _ = self.handle(event);
+        }
+    }
+
+    /// Carry out the given message, failing if the SDR rejected a change.
+    fn handle(&mut self, event: ControlTaskEvent) -> std::io::Result<()> {
+        match event {
+            ControlTaskEvent::SetFreq(freq) => {
+                self.freq = Some(freq);
+                self.tune();
+
+                // Chunks read from here on are at least partly from the new frequency.
+                self.advance();
+            },
+            ControlTaskEvent::SetCorrection(ppm) => {
+                self.correction = ppm;
+                self.tune();
+                self.configure();
+            },
+            ControlTaskEvent::SetGain(gain) => {
+                try!(self.sdr().set_tuner_gain(gain));
+                self.manual_gain = gain;
+                self.update(|s| s.gain = Some(gain));
+            },
+            ControlTaskEvent::SetAgc(true) => {
+                try!(self.sdr().enable_agc());
+                self.update(|s| s.gain = None);
+            },
+            ControlTaskEvent::SetAgc(false) => {
+                let gain = self.manual_gain;
+
+                try!(self.sdr().set_tuner_gain(gain));
+                self.update(|s| s.gain = Some(gain));
+            },
+            ControlTaskEvent::SetPpm(ppm) => {
+                try!(self.sdr().set_ppm(ppm));
+                self.update(|s| s.ppm = ppm);
+            },
+            ControlTaskEvent::SetDirectSampling(mode) => {
+                try!(self.sdr().set_direct_sampling(mode));
+                self.update(|s| s.direct_sampling = mode);
+            },
+            ControlTaskEvent::SetBiasTee(on) => {
+                try!(self.sdr().set_bias_tee(on));
+                self.update(|s| s.bias_tee = on);
+            },
+            ControlTaskEvent::DeviceLost => self.recover(),
+            ControlTaskEvent::Apply(events, reply) => {
+                let mut result = Ok(());
+
+                for e in events {
+                    result = self.handle(e);
+
+                    if result.is_err() {
+                        break;
+                    }
+                }
+
+                // Nobody is left to tell if the hub has gone away.
+                let _ = reply.send(result);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Reopen the lost SDR, retrying with increasing delays, and restore its settings.
+    fn recover(&mut self) {
+        if self.reopen.is_none() {
+            panic!("sdr was lost");
+        }
+
+        self.report_status(false, 0);
+
+        // The old handle may still hold the device, which would keep it from reopening.
+        self.sdr = None;
+
+        let mut delay = REOPEN_DELAY;
+        let mut attempts = 0;
+
         loop {
-            match self.events.recv().expect("unable to receive controller event") {
-                ControlTaskEvent::SetFreq(freq) =>
-                    self.sdr.set_center_freq(freq).expect("unable to set frequency"),
+            thread::sleep(Duration::from_secs(delay));
+
+            attempts += 1;
+
+            let result = match self.reopen {
+                Some(ref mut open) => open(),
+                None => unreachable!(),
+            };
+
+            match result {
+                Ok(sdr) => {
+                    self.sdr = Some(sdr);
+                    break;
+                },
+                Err(_) => {
+                    self.report_status(false, attempts);
+                    delay = std::cmp::min(delay * 2, REOPEN_MAX_DELAY);
+                },
+            }
+        }
+
+        self.restore();
+
+        // Samples from before the loss don't continue into those after it, so receivers
+        // start over as after a retune.
+        self.advance();
+
+        self.report_status(true, attempts);
+    }
+
+    /// Apply the current settings and frequency to a freshly opened SDR.
+    fn restore(&mut self) {
+        if let Some(s) = self.settings.clone() {
+            // Failures are left for the reader to notice if the SDR was lost again.
+            let _ = self.sdr().set_sample_rate(s.sample_rate);
+            let _ = self.sdr().set_ppm(s.ppm);
+
+            let _ = match s.gain {
+                Some(g) => self.sdr().set_tuner_gain(g),
+                None => self.sdr().enable_agc(),
+            };
+
+            if s.direct_sampling != 0 {
+                let _ = self.sdr().set_direct_sampling(s.direct_sampling);
+            }
+
+            if s.bias_tee {
+                let _ = self.sdr().set_bias_tee(true);
             }
         }
+
+        self.tune();
+    }
+
+    /// Send the connection status of the SDR and the number of attempts made to reopen
+    /// it to the hub.
+    fn report_status(&self, connected: bool, attempts: u32) {
+        if let Some(ref hub) = self.hub {
+            hub.send(HubEvent::UpdateSdrStatus(connected, attempts))
+                .expect("unable to send sdr status");
+        }
+    }
+
+    /// Modify the current settings with the given function and report them.
+    fn update<F: FnOnce(&mut SdrSettings)>(&mut self, f: F) {
+        if let Some(ref mut s) = self.settings {
+            f(s);
+        }
+
+        self.report();
+        self.configure();
+    }
+
+    /// Send the current settings to the hub.
+    fn report(&self) {
+        if let (&Some(ref hub), &Some(ref s)) = (&self.hub, &self.settings) {
+            hub.send(HubEvent::State(StateEvent::UpdateSdr(s.clone())))
+                .expect("unable to send sdr settings");
+        }
+    }
+
+    /// Send the current settings and correction to the taps.
+    fn configure(&self) {
+        if let Some(ref s) = self.settings {
+            for tap in self.taps.iter() {
+                let _ = tap.send(TapEvent::Configured(s.clone(), self.correction));
+            }
+        }
+    }
+
+    /// Advance the tuning generation and notify taps of the frequency it's on.
+    fn advance(&mut self) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let freq = match self.freq {
+            Some(f) => f,
+            None => return,
+        };
+
+        // Taps see the raw stream, which is centered on the offset frequency.
+        let center = (freq as i64 + self.offset as i64) as u32;
+
+        for tap in self.taps.iter() {
+            let _ = tap.send(TapEvent::Tuned(center, generation));
+        }
+    }
+
+    /// Get the open SDR.
+    fn sdr(&mut self) -> &mut C {
+        self.sdr.as_mut().expect("sdr not open")
+    }
+
+    /// Tune the SDR to the current frequency with the correction and offset applied.
+    fn tune(&mut self) {
+        let freq = match self.freq {
+            Some(f) => f,
+            None => return,
+        };
+
+        // Recorded samples stay where they were captured, so an emulated tuner is given
+        // the frequency as it was recorded.
+        let corrected = if self.sdr().emulated() {
+            freq as f64
+        } else {
+            freq as f64 * (1.0 + self.correction as f64 / 1.0e6)
+        };
+
+        let center = corrected.round() as i64 + self.offset as i64;
+
+        // If the SDR was lost, the reader notices and it's reopened on this frequency.
+        if self.sdr().set_center_freq(center as u32).is_err() && self.reopen.is_none() {
+            panic!("unable to set frequency");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_valid_sample_rate() {
+        assert!(valid_sample_rate(240_000));
+        assert!(valid_sample_rate(960_000));
+        assert!(valid_sample_rate(1_200_000));
+        assert!(valid_sample_rate(2_400_000));
+        assert!(valid_sample_rate(3_120_000));
+
+        assert!(!valid_sample_rate(0));
+        assert!(!valid_sample_rate(250_000));
+        assert!(!valid_sample_rate(480_000));
+        assert!(!valid_sample_rate(1_000_000));
+        assert!(!valid_sample_rate(3_360_000));
+        assert!(!valid_sample_rate(2_048_000));
+
+        // The documented bounds are the extremes of the supported set.
+        let fast = (1..20).map(|k| k * SDR_SAMPLE_RATE)
+                          .filter(|&r| r > SDR_SAMPLE_RATE && valid_sample_rate(r))
+                          .collect::<Vec<_>>();
+        assert_eq!(fast.first(), Some(&MIN_FAST_RATE));
+        assert_eq!(fast.last(), Some(&MAX_FAST_RATE));
+        assert_eq!(fast.len(), 10);
     }
 }
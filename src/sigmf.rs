@@ -0,0 +1,206 @@
+//! Metadata in the [SigMF](https://github.com/gnuradio/SigMF) format.
+//!
+//! A SigMF recording is a pair of files: `BASE.sigmf-data` holding the raw samples and
+//! `BASE.sigmf-meta` holding JSON that describes them. Each "capture segment" in the
+//! metadata marks the sample index where the SDR was retuned.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std;
+
+use serde_json;
+
+/// SigMF version written into metadata.
+pub const VERSION: &'static str = "1.0.0";
+
+/// Datatype of interleaved unsigned 8-bit I/Q samples.
+pub const DATATYPE_CU8: &'static str = "cu8";
+
+/// Version of the `p25rx` extension namespace written into metadata.
+pub const EXTENSION_VERSION: &'static str = "1.0.0";
+
+/// Get the data and metadata paths for the recording with the given base path.
+///
+/// The base path may also be the path of either file of the pair.
+pub fn paths<P: AsRef<Path>>(base: P) -> (PathBuf, PathBuf) {
+    let base = base.as_ref();
+
+    let base = match base.extension().and_then(|e| e.to_str()) {
+        Some("sigmf-data") | Some("sigmf-meta") => base.with_extension(""),
+        _ => base.to_path_buf(),
+    };
+
+    let mut data = base.clone().into_os_string();
+    data.push(".sigmf-data");
+
+    let mut meta = base.into_os_string();
+    meta.push(".sigmf-meta");
+
+    (data.into(), meta.into())
+}
+
+/// Top-level metadata object.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Meta {
+    pub global: Global,
+    pub captures: Vec<Capture>,
+    #[serde(default)]
+    pub annotations: Vec<serde_json::Value>,
+}
+
+impl Meta {
+    /// Create new metadata with the given global fields and no capture segments.
+    pub fn new(global: Global) -> Self {
+        Meta {
+            global: global,
+            captures: vec![],
+            annotations: vec![],
+        }
+    }
+
+    /// Parse metadata from the given stream.
+    pub fn read<R: Read>(stream: R) -> std::io::Result<Self> {
+        serde_json::from_reader(stream).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+        })
+    }
+
+    /// Replace the file at the given path with the metadata.
+    ///
+    /// The metadata is written to a temporary file first so the file at the given path
+    /// is always complete.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("sigmf-meta.tmp");
+
+        {
+            let mut f = try!(File::create(&tmp));
+
+            try!(serde_json::to_writer_pretty(&mut f, self).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+            }));
+
+            try!(f.write_all(b"\n"));
+        }
+
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Fields describing the whole recording.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Global {
+    #[serde(rename = "core:datatype")]
+    pub datatype: String,
+    #[serde(rename = "core:sample_rate")]
+    pub sample_rate: f64,
+    #[serde(rename = "core:version")]
+    pub version: String,
+    /// Extension namespaces used by the metadata.
+    #[serde(rename = "core:extensions", default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<Extension>,
+    #[serde(rename = "core:recorder", default, skip_serializing_if = "Option::is_none")]
+    pub recorder: Option<String>,
+    /// SDR frequency correction (ppm).
+    #[serde(rename = "p25rx:ppm", default, skip_serializing_if = "Option::is_none")]
+    pub ppm: Option<i32>,
+    /// Manual tuner gain (dB), or `None` if the tuner AGC was used.
+    #[serde(rename = "p25rx:gain", default, skip_serializing_if = "Option::is_none")]
+    pub gain: Option<f64>,
+}
+
+/// Declaration of an extension namespace.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Extension {
+    pub name: String,
+    pub version: String,
+    /// Whether readers can ignore the namespace's fields.
+    pub optional: bool,
+}
+
+impl Extension {
+    /// Declare the `p25rx` namespace of SDR settings.
+    pub fn p25rx() -> Self {
+        Extension {
+            name: "p25rx".to_string(),
+            version: EXTENSION_VERSION.to_string(),
+            optional: true,
+        }
+    }
+}
+
+/// Segment of samples captured at a single center frequency and set of SDR settings.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Capture {
+    /// Index of the first sample in the segment.
+    #[serde(rename = "core:sample_start")]
+    pub sample_start: u64,
+    /// Center frequency (Hz).
+    #[serde(rename = "core:frequency", default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<f64>,
+    /// ISO 8601 timestamp of the first sample.
+    #[serde(rename = "core:datetime", default, skip_serializing_if = "Option::is_none")]
+    pub datetime: Option<String>,
+    /// SDR frequency correction (ppm).
+    #[serde(rename = "p25rx:ppm", default, skip_serializing_if = "Option::is_none")]
+    pub ppm: Option<i32>,
+    /// Manual tuner gain (dB), or `None` if the tuner AGC was used.
+    #[serde(rename = "p25rx:gain", default, skip_serializing_if = "Option::is_none")]
+    pub gain: Option<f64>,
+    /// Fine frequency correction (ppm) applied on top of the SDR's own.
+    #[serde(rename = "p25rx:correction", default,
+            skip_serializing_if = "Option::is_none")]
+    pub correction: Option<f64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+    use serde_json;
+
+    #[test]
+    fn test_paths() {
+        let (data, meta) = paths("rec/cap");
+        assert_eq!(data, PathBuf::from("rec/cap.sigmf-data"));
+        assert_eq!(meta, PathBuf::from("rec/cap.sigmf-meta"));
+
+        assert_eq!(paths("rec/cap.sigmf-meta"), (data.clone(), meta.clone()));
+        assert_eq!(paths("rec/cap.sigmf-data"), (data, meta));
+
+        assert_eq!(paths("rec/cap.1").0, PathBuf::from("rec/cap.1.sigmf-data"));
+    }
+
+    #[test]
+    fn test_serde() {
+        let mut meta = Meta::new(Global {
+            datatype: DATATYPE_CU8.to_string(),
+            sample_rate: 240000.0,
+            version: VERSION.to_string(),
+            extensions: vec![Extension::p25rx()],
+            recorder: Some("p25rx".to_string()),
+            ppm: Some(-2),
+            gain: None,
+        });
+
+        meta.captures.push(Capture {
+            sample_start: 0,
+            frequency: Some(851012500.0),
+            datetime: None,
+            ppm: None,
+            gain: Some(28.0),
+            correction: Some(0.5),
+        });
+
+        let json = serde_json::to_string(&meta).unwrap();
+
+        assert!(json.contains("\"core:datatype\":\"cu8\""));
+        assert!(json.contains("\"p25rx:ppm\":-2"));
+        assert!(json.contains("\"name\":\"p25rx\""));
+        assert!(json.contains("\"p25rx:gain\":28"));
+        assert!(json.contains("\"core:sample_start\":0"));
+
+        assert_eq!(Meta::read(json.as_bytes()).unwrap(), meta);
+    }
+}
@@ -0,0 +1,295 @@
+//! Spectrum analysis of raw SDR samples.
+//!
+//! Every frame period, a handful of consecutive blocks are windowed, transformed, and
+//! their power averaged, giving a spectrum of the whole sampled band that's cheap enough
+//! to compute alongside demodulation.
+
+use std::f32::consts::PI;
+
+use num::complex::Complex32;
+
+/// Number of blocks averaged into each frame.
+const AVERAGE: usize = 8;
+
+/// Smallest supported transform size.
+pub const MIN_SIZE: usize = 64;
+
+/// Largest supported transform size.
+pub const MAX_SIZE: usize = 8192;
+
+/// Compute the discrete Fourier transform of the given samples in place, where the number
+/// of samples must be a power of two.
+pub fn fft(buf: &mut [Complex32]) {
+    let n = buf.len();
+    assert!(n.is_power_of_two());
+
+    let bits = n.trailing_zeros();
+
+    // Put the samples in bit-reversed order so each stage works on adjacent pairs.
+    for i in 0..n {
+        let j = (0..bits).fold(0, |j, b| (j << 1) | ((i >> b) & 1));
+
+        if i < j {
+            buf.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+
+    while len <= n {
+        let step = Complex32::from_polar(&1.0, &(-2.0 * PI / len as f32));
+
+        for start in (0..n / len).map(|b| b * len) {
+            let mut w = Complex32::new(1.0, 0.0);
+
+            for k in 0..len / 2 {
+                let a = buf[start + k];
+                let b = buf[start + k + len / 2] * w;
+
+                buf[start + k] = a + b;
+                buf[start + k + len / 2] = a - b;
+
+                w = w * step;
+            }
+        }
+
+        len *= 2;
+    }
+}
+
+/// Check if the given transform size is supported.
+pub fn valid_size(size: usize) -> bool {
+    size.is_power_of_two() && size >= MIN_SIZE && size <= MAX_SIZE
+}
+
+/// Averaged power spectrum of the sampled band.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Spectrum {
+    /// Center frequency (Hz) the SDR was sampling at, which can be offset from the
+    /// channel being received.
+    pub center: u32,
+    /// Sample rate (Hz) of the analyzed samples, which is also the width of the
+    /// spectrum.
+    pub rate: u32,
+    /// Power (dB relative to a full-scale tone) of each frequency bin, from the lowest
+    /// frequency to the highest, with the center frequency in the middle bin.
+    pub bins: Vec<f32>,
+}
+
+/// Computes averaged spectrum frames at a fixed rate.
+pub struct SpectrumAnalyzer {
+    /// Center frequency (Hz) of the input, if known.
+    center: Option<u32>,
+    /// Sample rate (Hz) of the input.
+    rate: u32,
+    /// Window applied to each block before its transform.
+    window: Vec<f32>,
+    /// Number of input samples between the starts of consecutive frames.
+    interval: usize,
+    /// Samples collected for the current block.
+    block: Vec<Complex32>,
+    /// Sum of the power in each bin over the blocks of the current frame.
+    power: Vec<f32>,
+    /// Number of blocks summed into the current frame.
+    blocks: usize,
+    /// Number of samples to skip before starting the next frame.
+    skip: usize,
+}
+
+impl SpectrumAnalyzer {
+    /// Create a new `SpectrumAnalyzer` for samples at the given rate (Hz), producing the
+    /// given number of frames per second with the given number of bins, which must be a
+    /// supported transform size.
+    ///
+    /// No frames are produced until the center frequency is given with `retune`.
+    pub fn new(rate: u32, size: usize, fps: u32) -> Self {
+        assert!(valid_size(size));
+        assert!(fps > 0);
+
+        // Hann window, which keeps strong signals from leaking far into nearby bins.
+        let window = (0..size).map(|i| {
+            0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos()
+        }).collect();
+
+        SpectrumAnalyzer {
+            center: None,
+            rate: rate,
+            window: window,
+            interval: (rate / fps) as usize,
+            block: Vec::with_capacity(size),
+            power: vec![0.0; size],
+            blocks: 0,
+            skip: 0,
+        }
+    }
+
+    /// Analyze samples from the given center frequency (Hz) from here on, discarding
+    /// the current frame so it doesn't mix in samples from elsewhere.
+    pub fn retune(&mut self, center: u32) {
+        self.center = Some(center);
+        self.block.clear();
+        self.clear();
+        self.skip = 0;
+    }
+
+    /// Feed in the given samples, returning a frame if one was completed.
+    ///
+    /// If the samples complete more than one frame, only the last is returned.
+    pub fn feed(&mut self, samples: &[Complex32]) -> Option<Spectrum> {
+        let center = match self.center {
+            Some(c) => c,
+            None => return None,
+        };
+
+        let mut frame = None;
+
+        for &s in samples {
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+
+            self.block.push(s);
+
+            if self.block.len() < self.window.len() {
+                continue;
+            }
+
+            self.add_block();
+
+            if self.blocks == AVERAGE {
+                frame = Some(self.finish(center));
+                self.skip = self.interval.saturating_sub(AVERAGE * self.window.len());
+            }
+        }
+
+        frame
+    }
+
+    /// Transform the current block and add its power to the current frame.
+    fn add_block(&mut self) {
+        for (s, &w) in self.block.iter_mut().zip(self.window.iter()) {
+            *s = *s * w;
+        }
+
+        fft(&mut self.block[..]);
+
+        for (p, s) in self.power.iter_mut().zip(self.block.iter()) {
+            *p += s.norm_sqr();
+        }
+
+        self.block.clear();
+        self.blocks += 1;
+    }
+
+    /// Convert the current frame to a spectrum at the given center frequency (Hz) and
+    /// start a new frame.
+    fn finish(&mut self, center: u32) -> Spectrum {
+        let size = self.power.len();
+
+        // A full-scale tone puts the window's sum into its bin.
+        let gain = self.window.iter().fold(0.0, |s, &w| s + w);
+        let norm = gain * gain * self.blocks as f32;
+
+        // Rotate the bins so negative frequencies come first.
+        let bins = (0..size).map(|i| {
+            let p = self.power[(i + size / 2) % size] / norm;
+            10.0 * p.max(1.0e-20).log10()
+        }).collect();
+
+        self.clear();
+
+        Spectrum {
+            center: center,
+            rate: self.rate,
+            bins: bins,
+        }
+    }
+
+    /// Clear the power summed into the current frame.
+    fn clear(&mut self) {
+        for p in self.power.iter_mut() {
+            *p = 0.0;
+        }
+
+        self.blocks = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f32::consts::PI;
+    use num::complex::Complex32;
+
+    #[test]
+    fn test_fft() {
+        let input = (0..16).map(|i| {
+            Complex32::new((i as f32 * 0.7).sin(), (i as f32 * 1.3).cos() * 0.5)
+        }).collect::<Vec<_>>();
+
+        let mut out = input.clone();
+        fft(&mut out[..]);
+
+        for k in 0..16 {
+            let expected = input.iter().enumerate().fold(Complex32::new(0.0, 0.0),
+                |s, (n, &x)| {
+                    s + x * Complex32::from_polar(&1.0,
+                        &(-2.0 * PI * (k * n) as f32 / 16.0))
+                });
+
+            assert!((out[k] - expected).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_valid_size() {
+        assert!(valid_size(64));
+        assert!(valid_size(1024));
+        assert!(valid_size(8192));
+        assert!(!valid_size(32));
+        assert!(!valid_size(1000));
+        assert!(!valid_size(16384));
+    }
+
+    #[test]
+    fn test_analyzer() {
+        // A tone a quarter of the sample rate above the tuned frequency.
+        let samples = (0..240000).map(|i| {
+            Complex32::from_polar(&1.0, &(2.0 * PI * 0.25 * i as f32))
+        }).collect::<Vec<_>>();
+
+        let mut sa = SpectrumAnalyzer::new(240000, 64, 4);
+
+        // Nothing is analyzed before the center frequency is known.
+        assert_eq!(sa.feed(&samples[..64 * 8]), None);
+
+        sa.retune(851000000);
+
+        // Not enough samples for a frame.
+        assert_eq!(sa.feed(&samples[..64 * 8 - 1]), None);
+
+        let frame = sa.feed(&samples[64 * 8 - 1..64 * 8]).unwrap();
+        assert_eq!(frame.center, 851000000);
+        assert_eq!(frame.rate, 240000);
+        assert_eq!(frame.bins.len(), 64);
+
+        // The tone lands at 0dB, halfway between the center and the top of the band,
+        // and the Hann window keeps it out of bins a few away.
+        assert!(frame.bins[48].abs() < 0.1);
+        assert!(frame.bins[44] < -60.0);
+        assert!(frame.bins[52] < -60.0);
+
+        // The rest of the frame period is skipped.
+        assert_eq!(sa.feed(&samples[64 * 8..60000 - 1]), None);
+        assert!(sa.feed(&samples[60000 - 1..60000 + 64 * 8]).is_some());
+
+        // Retuning starts a new frame right away at the new frequency.
+        assert_eq!(sa.feed(&samples[..64 * 4]), None);
+        sa.retune(852000000);
+        assert_eq!(sa.feed(&samples[..64 * 8 - 1]), None);
+
+        let frame = sa.feed(&samples[64 * 8 - 1..64 * 8]).unwrap();
+        assert_eq!(frame.center, 852000000);
+    }
+}
@@ -0,0 +1,68 @@
+//! Carrier detection.
+//!
+//! A channel with nothing on it still produces samples, and decoding them only wastes
+//! time and keeps the receiver waiting for messages that will never arrive. Carrier is
+//! detected by splitting each chunk's power into signal and noise, so the threshold is
+//! relative to the noise floor at the current gain rather than an absolute level.
+
+/// SNR (dB) above which a carrier is considered to have appeared.
+const OPEN: f32 = 3.0;
+
+/// SNR (dB) below which a carrier is considered to have gone away.
+const CLOSE: f32 = 0.0;
+
+/// Detects whether a carrier is present on the channel, with hysteresis so a signal
+/// hovering near the threshold doesn't flap.
+pub struct Squelch {
+    /// Whether a carrier is currently present.
+    open: bool,
+}
+
+impl Squelch {
+    /// Create a new `Squelch` that initially considers a carrier present, so nothing is
+    /// discarded before the channel has been measured.
+    pub fn new() -> Self {
+        Squelch {
+            open: true,
+        }
+    }
+
+    /// Update with the given SNR (dB) estimated over a chunk, returning whether a carrier
+    /// is present.
+    pub fn feed(&mut self, snr: f32) -> bool {
+        // An estimate that can't be computed doesn't change the state.
+        if snr.is_nan() {
+            return self.open;
+        }
+
+        self.open = if self.open {
+            snr >= CLOSE
+        } else {
+            snr > OPEN
+        };
+
+        self.open
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std;
+
+    #[test]
+    fn test_squelch() {
+        let mut s = Squelch::new();
+
+        assert!(s.feed(20.0));
+        assert!(s.feed(1.0));
+        assert!(!s.feed(-1.0));
+        assert!(!s.feed(2.0));
+        assert!(!s.feed(std::f32::NAN));
+        assert!(!s.feed(std::f32::NEG_INFINITY));
+        assert!(s.feed(4.0));
+        assert!(s.feed(std::f32::NAN));
+        assert!(s.feed(std::f32::INFINITY));
+        assert!(!s.feed(-5.0));
+    }
+}
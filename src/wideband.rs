@@ -0,0 +1,271 @@
+//! Wideband operation, where several channels are extracted from one SDR stream.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, Receiver};
+
+use mio;
+use num::complex::Complex32;
+use num::traits::Zero;
+use pool::Pool;
+use throttle::Throttler;
+
+use consts::{BUF_SAMPLES, SDR_SAMPLE_RATE, BASEBAND_SAMPLE_RATE};
+use demod::{Demodulator, Modulation, iq_samples};
+use dsp::{Nco, Filterbank};
+use hub::{HubEvent, StateEvent};
+use recv::RecvEvent;
+use sdr::{ControlTaskEvent, SampleChunk};
+use spectrum::SpectrumAnalyzer;
+
+/// Half the bandwidth of a P25 channel (Hz).
+const HALF_CHANNEL: u32 = 6250;
+
+/// Get the lowest and highest channel frequencies (Hz) that can be extracted from an SDR
+/// at the given center frequency and sample rate (Hz).
+pub fn channel_range(center: u32, rate: u32) -> (u32, u32) {
+    let reach = rate / 2 - HALF_CHANNEL;
+    (center.saturating_sub(reach), center.saturating_add(reach))
+}
+
+/// Splits a wideband I/Q stream into narrowband channels and demodulates each one to
+/// C4FM baseband.
+///
+/// The stream is split into bins spaced half the baseband rate apart, each decimated to
+/// the baseband rate, by a filterbank shared across channels. Each channel then takes the
+/// bin nearest its frequency and shifts the rest of the way to it at the baseband rate.
+pub struct ChannelizerTask {
+    /// Center frequency of the SDR (Hz).
+    center: u32,
+    /// Sample rate of the SDR (Hz).
+    rate: u32,
+    /// Frequency correction (ppm) applied to every channel.
+    correction: f32,
+    /// Modulation of added channels.
+    modulation: Modulation,
+    /// Splits the stream into bins at the baseband rate.
+    bank: Filterbank,
+    /// Extracted channels.
+    channels: Vec<Channel>,
+    /// Channel for receiving I/Q sample chunks.
+    reader: Receiver<SampleChunk>,
+    /// Spectrum of the wideband samples, if enabled, and the hub it's reported to.
+    spectrum: Option<(SpectrumAnalyzer, mio::channel::Sender<HubEvent>)>,
+}
+
+impl ChannelizerTask {
+    /// Create a new `ChannelizerTask` over the SDR at the given center frequency and
+    /// sample rate (Hz), receiving I/Q sample chunks from the given channel.
+    ///
+    /// The sample rate must be a multiple of the normal SDR sample rate.
+    pub fn new(center: u32, rate: u32, reader: Receiver<SampleChunk>) -> Self {
+        assert!(rate % SDR_SAMPLE_RATE == 0);
+
+        ChannelizerTask {
+            center: center,
+            rate: rate,
+            correction: 0.0,
+            modulation: Modulation::C4fm,
+            bank: Filterbank::new((2 * rate / BASEBAND_SAMPLE_RATE) as usize),
+            channels: vec![],
+            reader: reader,
+            spectrum: None,
+        }
+    }
+
+    /// Report spectrum frames of the wideband samples to the given hub with the given
+    /// number of bins, which must be a supported transform size, at the given frames per
+    /// second.
+    pub fn enable_spectrum(&mut self, size: usize, fps: u32,
+                           hub: mio::channel::Sender<HubEvent>)
+    {
+        let mut sa = SpectrumAnalyzer::new(self.rate, size, fps);
+        sa.retune(self.center);
+
+        self.spectrum = Some((sa, hub));
+    }
+
+    /// Demodulate channels added after this with the given modulation.
+    pub fn set_modulation(&mut self, modulation: Modulation) {
+        self.modulation = modulation;
+    }
+
+    /// Add a channel that's tuned by messages from the first given channel and sends
+    /// baseband sample chunks to the second given channel, and signal power to the given
+    /// hub (if any), advancing the given tuning generation after each retune.
+    ///
+    /// A frequency correction sent to any channel applies to all of them, since they
+    /// share the SDR.
+    pub fn add_channel(&mut self,
+                       tune: Receiver<ControlTaskEvent>,
+                       chan: Sender<RecvEvent>,
+                       hub: Option<mio::channel::Sender<HubEvent>>,
+                       generation: Arc<AtomicUsize>)
+    {
+        self.channels.push(Channel {
+            freq: None,
+            bin: 0,
+            nco: Nco::new(0.0),
+            buf: Vec::with_capacity(BUF_SAMPLES),
+            demod: Demodulator::new(self.modulation),
+            tune: tune,
+            chan: chan,
+            hub: hub,
+            generation: generation,
+            notifier: Throttler::new(16),
+            pool: Pool::with_capacity(16, || vec![0.0; BUF_SAMPLES]),
+        });
+    }
+
+    /// Begin channelizing, blocking the current thread until the I/Q source is
+    /// exhausted.
+    pub fn run(&mut self) {
+        let mut samples = vec![Complex32::zero(); BUF_SAMPLES];
+        let bins = self.bank.bins();
+
+        while let Ok(chunk) = self.reader.recv() {
+            iq_samples(&chunk.bytes[..], &mut samples[..]);
+
+            if let Some((ref mut sa, ref hub)) = self.spectrum {
+                if let Some(frame) = sa.feed(&samples[..]) {
+                    hub.send(HubEvent::State(StateEvent::UpdateSpectrum(frame)))
+                        .expect("unable to send spectrum");
+                }
+            }
+
+            for ch in self.channels.iter_mut() {
+                ch.retune(self.center, self.rate, &mut self.correction);
+            }
+
+            for ch in self.channels.iter_mut() {
+                ch.select(self.center, self.rate, self.correction, bins);
+            }
+
+            for &s in samples.iter() {
+                if self.bank.feed(s) {
+                    for ch in self.channels.iter_mut() {
+                        ch.extract(&self.bank);
+                    }
+                }
+            }
+
+            for ch in self.channels.iter_mut() {
+                ch.feed();
+            }
+        }
+
+        for ch in self.channels.iter() {
+            ch.chan.send(RecvEvent::EndOfStream).expect("unable to send end of stream");
+        }
+    }
+}
+
+/// Single narrowband channel within the wideband stream.
+struct Channel {
+    /// Channel frequency (Hz), or `None` if the channel hasn't been tuned.
+    freq: Option<u32>,
+    /// Filterbank bin nearest the channel.
+    bin: usize,
+    /// Shifts the channel from its offset within the bin to DC.
+    nco: Nco,
+    /// Samples extracted from the current chunk at the baseband rate.
+    buf: Vec<Complex32>,
+    /// Filters and demodulates the channel.
+    demod: Demodulator,
+    /// Channel for tuning messages.
+    tune: Receiver<ControlTaskEvent>,
+    /// Channel for sending baseband sample chunks.
+    chan: Sender<RecvEvent>,
+    /// Channel for the hub, if this channel reports signal power.
+    hub: Option<mio::channel::Sender<HubEvent>>,
+    /// Tuning generation of the channel.
+    generation: Arc<AtomicUsize>,
+    /// Used to reduce the number of signal level messages sent.
+    notifier: Throttler,
+    /// Baseband sample chunks.
+    pool: Pool<Vec<f32>>,
+}
+
+impl Channel {
+    /// Apply any pending tuning messages, updating the given shared correction (ppm).
+    fn retune(&mut self, center: u32, rate: u32, correction: &mut f32) {
+        while let Ok(event) = self.tune.try_recv() {
+            match event {
+                ControlTaskEvent::SetFreq(freq) => {
+                    let (min, max) = channel_range(center, rate);
+
+                    // Frequencies outside the band are ignored, leaving the channel on
+                    // its previous frequency.
+                    if freq >= min && freq <= max {
+                        self.freq = Some(freq);
+                    }
+
+                    // Every chunk from here on is extracted at the new frequency, and
+                    // the generation still advances for an ignored frequency so the
+                    // receiver doesn't wait on it.
+                    self.generation.fetch_add(1, Ordering::SeqCst);
+                },
+                ControlTaskEvent::SetCorrection(ppm) => *correction = ppm,
+                // Other settings apply to the SDR itself.
+                _ => {},
+            }
+        }
+    }
+
+    /// Start a new chunk, choosing the bin and fine shift that bring the channel to DC
+    /// from a filterbank of the given number of bins over an SDR at the given center
+    /// frequency and sample rate (Hz) with the given correction (ppm).
+    fn select(&mut self, center: u32, rate: u32, correction: f32, bins: usize) {
+        self.buf.clear();
+
+        let freq = match self.freq {
+            Some(f) => f,
+            None => return,
+        };
+
+        // Shift the channel as if the SDR were tuned to it with the correction applied.
+        let offset = freq as f64 * (1.0 + correction as f64 / 1.0e6) - center as f64;
+        let spacing = rate as f64 / bins as f64;
+        let nearest = (offset / spacing).round();
+
+        // Bins above the center wrap around to negative frequencies.
+        self.bin = ((nearest as i64 + bins as i64) % bins as i64) as usize;
+
+        let residual = offset - nearest * spacing;
+        self.nco.set_freq((-residual / BASEBAND_SAMPLE_RATE as f64) as f32);
+    }
+
+    /// Take the channel's sample from the latest output of the given filterbank.
+    fn extract(&mut self, bank: &Filterbank) {
+        if self.freq.is_some() {
+            self.buf.push(self.nco.mix(bank.bin(self.bin)));
+        }
+    }
+
+    /// Demodulate the samples extracted from the current chunk.
+    fn feed(&mut self) {
+        if self.freq.is_none() {
+            return;
+        }
+
+        self.demod.filter(&mut self.buf[..]);
+
+        let mut baseband = self.pool.checkout().expect("unable to allocate baseband");
+
+        // This is safe because the decimated length is less than the allocated length.
+        unsafe { baseband.set_len(self.buf.len()); }
+
+        let mut stats = self.demod.demod(&self.buf[..], &mut baseband[..]);
+        stats.generation = self.generation.load(Ordering::SeqCst);
+
+        if let Some(ref hub) = self.hub {
+            self.notifier.throttle(|| {
+                hub.send(HubEvent::UpdateSignalPower(stats.power))
+                    .expect("unable to send signal power");
+            });
+        }
+
+        self.chan.send(RecvEvent::Baseband(baseband, stats))
+            .expect("unable to send baseband");
+    }
+}